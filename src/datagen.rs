@@ -3,16 +3,16 @@
 #![feature(is_some_and)]
 #![feature(adt_const_params)]
 
-use rand::Rng;
+use rand::{Rng, SeedableRng};
 use core::marker::ConstParamTy;
 
 use std::fs::File;
-use std::fs::OpenOptions;
 use std::path::Path;
 use std::io::{BufWriter, BufReader, Write, BufRead};
 
 use itertools::Itertools;
 
+use synthesizer::enumerative::{Budget, Library, RankModel, RankingTask, SynthConfig, Weights};
 use synthesizer::vsa::{Lit, Fun, AST};
 
 type Program = AST<Lit, Fun>;
@@ -381,28 +381,43 @@ impl<'a> Examples<'a> {
         Examples { prog, inps }
     }
 
+    // the first few inputs as examples and some more to check against
+    pub fn ranking_task(&self, n_examples: usize, n_tests: usize) -> RankingTask {
+        let inps = self.inps.iter().map(|inp| Lit::StringConst(inp.clone()));
+        RankingTask {
+            examples: inps
+                .clone()
+                .take(n_examples)
+                .map(|inp| (inp.clone(), self.prog.eval(&inp)))
+                .collect(),
+            tests: inps.skip(n_examples).take(n_tests).collect(),
+            program: self.prog.clone(),
+        }
+    }
+
     // might be good to hashcons
     // i feel like this is stupid and I should just
     // do it in one pass
     // pub fn traces(&self) -> Vec<Trace> {
     //     todo!()
     // }
-    pub fn write_traces<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
-        let f = OpenOptions::new().create(true).append(true).open(path)?;
-        let mut writer = BufWriter::new(f);
+    pub fn write_traces<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.trace_to(&mut writer)?;
+        writer.flush()
+    }
 
+    pub fn trace_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
         for inp in self.inps {
             let inp_lit = Lit::StringConst(inp.clone());
             let out = self.prog.eval(&inp_lit);
             let first_trace = im::vector![format!("{} → {};", inp_lit, out)];
-            Self::trace(first_trace, self.prog, &inp_lit, &mut writer)?;
+            Self::trace(first_trace, self.prog, &inp_lit, writer)?;
         }
-
-        writer.flush()?;
         Ok(())
     }
 
-    fn trace(parent_trace: iVec<String>, prog: &'a Program, inp: &Lit, writer: &mut BufWriter<File>) -> std::io::Result<()> {
+    fn trace(parent_trace: iVec<String>, prog: &'a Program, inp: &Lit, writer: &mut impl Write) -> std::io::Result<()> {
         // 1. dfs program trace, add stuff to imlist
         // 2. at the end of each path, write stuff from imlist, it was reversed
 
@@ -427,6 +442,8 @@ impl<'a> Examples<'a> {
                     Self::trace(next_trace.clone(), arg, inp, writer)?;
                 }
             }
+            // generated programs never have holes
+            AST::Hole(_) => unreachable!(),
         }
 
         Ok(())
@@ -454,7 +471,188 @@ pub fn gen_inputs() -> Vec<String> {
     res
 }
 
-// TODO: generate some inputs, generate a bunch of programs, trace all the programs
+const STRING: TyIndex = 0;
+const REGEX: TyIndex = 1;
+const LOC: TyIndex = 2;
+
+// The default `SynthConfig`'s operators and seeds, typed like
+// data/strings.grammar so every program can be evaluated
+pub fn default_programs() -> impl Iterator<Item = Program> {
+    let strings = ["", " ", "."].map(|s| (STRING, AST::Lit(Lit::StringConst(s.to_string()))));
+    let regexes = ["\\d", "\\b", "[a-z]", "[A-Z]"]
+        .map(|s| (REGEX, AST::Lit(Lit::StringConst(s.to_string()))));
+    let locs = [Lit::LocConst(0), Lit::LocConst(1), Lit::LocEnd].map(|l| (LOC, AST::Lit(l)));
+    let bank = std::iter::once((STRING, AST::Lit(Lit::Input)))
+        .chain(strings)
+        .chain(regexes)
+        .chain(locs)
+        .collect();
+
+    let ops = leak!(vec![
+        (Fun::Concat, vec![STRING, STRING], STRING),
+        (Fun::Slice, vec![LOC, LOC], STRING),
+        (Fun::Find, vec![STRING, STRING, LOC], LOC),
+        (Fun::Find, vec![STRING, REGEX, LOC], LOC),
+        (Fun::FindEnd, vec![STRING, STRING, LOC], LOC),
+        (Fun::FindEnd, vec![STRING, REGEX, LOC], LOC),
+    ]);
+    prog_gen(bank, ops, 3)
+}
+
+// `n` string programs picked at random from the first `pool`, each with its
+// own sample of `per_program` inputs. Programs that panic on any input, like
+// slices that cut a character in half, are skipped, and so are programs that
+// give the same outputs as a smaller one, which would mostly be concats of
+// empty strings otherwise.
+pub fn corpus(
+    programs: impl Iterator<Item = Program>,
+    inps: &[String],
+    pool: usize,
+    n: usize,
+    per_program: usize,
+    rng: &mut impl Rng,
+) -> Vec<(Program, Vec<String>)> {
+    use rand::seq::SliceRandom;
+
+    let mut seen = std::collections::HashSet::new();
+    let pool = programs
+        .take(pool)
+        .filter_map(|prog| {
+            let outs = std::panic::catch_unwind(|| {
+                inps.iter()
+                    .map(|inp| prog.eval(&Lit::StringConst(inp.clone())))
+                    .collect::<Vec<_>>()
+            })
+            .ok()?;
+            let strings = outs.iter().all(|out| matches!(out, Lit::StringConst(_)));
+            (strings && seen.insert(outs)).then_some(prog)
+        })
+        .collect::<Vec<_>>();
+
+    pool.choose_multiple(rng, n)
+        .map(|prog| (prog.clone(), inps.choose_multiple(rng, per_program).cloned().collect()))
+        .collect()
+}
+
+// how often each operator and constant shows up, for `SynthConfig::weights`
+pub fn train_weights(examples: &[Examples]) -> Result<Weights, Box<dyn std::error::Error>> {
+    let mut traces = Vec::new();
+    for e in examples {
+        e.trace_to(&mut traces)?;
+    }
+    Ok(Weights::train(&String::from_utf8(traces)?)?)
+}
+
+#[test]
+fn test_train_weights() {
+    let inps = ["Hello World", "ab 12", "x.y z", "Grace Hopper", "3.14"].map(String::from);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let corpus = corpus(default_programs(), &inps, 5000, 200, 3, &mut rng);
+    assert_eq!(corpus.len(), 200);
+    assert!(corpus.iter().any(|(prog, _)| prog.to_string().contains("find")));
+
+    let examples = corpus
+        .iter()
+        .map(|(prog, inps)| Examples::new(prog, inps))
+        .collect::<Vec<_>>();
+    let weights = train_weights(&examples).unwrap();
+
+    // nothing's left over from the last run
+    assert_eq!(weights.to_string(), train_weights(&examples).unwrap().to_string());
+
+    // the corpus never lowercases anything, and is mostly X
+    assert!(weights.fun_bits(Fun::Slice) < weights.fun_bits(Fun::Lowercase));
+    assert!(weights.lit_bits(&Lit::Input) < weights.lit_bits(&Lit::StringConst(".".to_string())));
+}
+
+#[test]
+fn test_fit_ranking() {
+    let inps = [
+        "Hello World", "ab 12", "x.y z", "Grace Hopper", "3.14", "a-b c", "Ada Lovelace 1815",
+        "tel. 555 0100", "id x9", "Mt. Fuji", "v2.0 beta", "New York NY",
+    ]
+    .map(String::from);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let corpus = corpus(default_programs(), &inps, 5000, 60, 11, &mut rng);
+
+    // with a single example, the default takes a constant whenever it can
+    let tasks = corpus
+        .iter()
+        .map(|(prog, inps)| Examples::new(prog, inps).ranking_task(1, 10))
+        .collect::<Vec<_>>();
+    let (train, held_out) = tasks.split_at(tasks.len() / 2);
+
+    // a bank limit instead of a timeout, so the test doesn't depend on how
+    // fast it runs
+    let config = SynthConfig::default().budget(Budget::default().max_bank_entries(5000));
+    let (model, skipped) = RankModel::fit(train, &config, 10, 0.1);
+    assert!(skipped < train.len());
+    assert_eq!(model, model.to_string().parse().unwrap());
+
+    // how many of the tasks it hasn't seen are synthesized as intended
+    let solved = |model: &RankModel| {
+        let config = config.clone().ranking(model.clone());
+        held_out
+            .iter()
+            .filter(|task| {
+                synthesizer::enumerative::duet(&task.examples, &config)
+                    .is_some_and(|prog| task.generalizes(&prog))
+            })
+            .count()
+    };
+    assert!(solved(&model) > solved(&RankModel::default()));
+}
+
+#[test]
+fn test_learn_library() {
+    let inps = [
+        "Hello World", "ab 12", "x.y z", "Grace Hopper", "3.14", "a-b c", "Ada Lovelace 1815",
+        "tel. 555 0100", "id x9", "Mt. Fuji", "v2.0 beta", "New York NY",
+    ]
+    .map(String::from);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let corpus = corpus(default_programs(), &inps, 5000, 200, 12, &mut rng);
+    let programs = corpus.iter().map(|(prog, _)| prog.clone()).collect::<Vec<_>>();
+    let library = Library::learn(&programs, 5);
+    assert_eq!(library.abstractions().len(), 5);
+
+    // it's all in the text, and loading it again defines the same functions
+    let path = std::env::temp_dir().join("synthesizer-test-library.txt");
+    library.save(&path).unwrap();
+    let loaded = Library::load(&path).unwrap();
+    assert_eq!(loaded.to_string(), library.to_string());
+    assert_eq!(loaded.funs().collect::<Vec<_>>(), library.funs().collect::<Vec<_>>());
+
+    // the corpus gets smaller with it
+    let total = |programs: &[Program]| programs.iter().map(Program::size).sum::<usize>();
+    let rewritten = programs.iter().map(|p| loaded.rewrite(p)).collect::<Vec<_>>();
+    assert!(total(&rewritten) < total(&programs));
+
+    // and programs that use it are found from two examples each
+    let config = SynthConfig::default().library(&loaded);
+    let tasks = corpus
+        .iter()
+        .filter(|(prog, _)| loaded.rewrite(prog) != *prog)
+        .map(|(prog, inps)| Examples::new(prog, inps).ranking_task(2, 10))
+        .take(20)
+        .collect::<Vec<_>>();
+    assert_eq!(tasks.len(), 20);
+    let mut used = 0;
+    for task in tasks.iter() {
+        let prog = synthesizer::enumerative::duet(&task.examples, &config).unwrap();
+        assert!(task.examples.iter().all(|(inp, out)| prog.eval(inp) == *out));
+        used += usize::from(loaded.funs().any(|fun| calls(&prog, fun)));
+    }
+    assert!(used > 0);
+
+    fn calls(prog: &Program, f: Fun) -> bool {
+        match prog {
+            AST::App { fun, args } => *fun == f || args.iter().any(|arg| calls(arg, f)),
+            _ => false,
+        }
+    }
+}
+
 fn main() {
     if false {
         let inps = gen_inputs();
@@ -469,34 +667,27 @@ fn main() {
     let inp_file = File::open("data/generated_inputs.txt").unwrap();
     let reader = BufReader::new(inp_file);
     let inps: Vec<_> = reader.lines().map(Result::unwrap).collect();
-    let programs = {
-        let bank = vec![
-            // TODO: better to separate regexes :/
-            AST::Lit(Lit::StringConst("\\.".to_string())),
-            AST::Lit(Lit::StringConst("\\d".to_string())),
-            AST::Lit(Lit::StringConst("\\b".to_string())),
-            AST::Lit(Lit::StringConst("[a-z]".to_string())),
-            AST::Lit(Lit::StringConst("[A-Z]".to_string())),
-            AST::Lit(Lit::Input),
-            AST::Lit(Lit::StringConst("".to_string())),
-            AST::Lit(Lit::StringConst(" ".to_string())),
-            AST::Lit(Lit::StringConst(".".to_string())),
-            AST::Lit(Lit::LocConst(0)),
-            AST::Lit(Lit::LocConst(1)),
-            AST::Lit(Lit::LocEnd),
-        ];
-        let ops = vec![
-            vec![],
-            vec![Fun::Concat, Fun::Slice, Fun::LocAdd],
-            vec![Fun::Find, Fun::FindEnd],
-        ];
-
-            ProgramGen::new(bank, ops)
-    };
 
-    for prog in programs {
-        dbg!(&prog);
-        let mut e = Examples::new(&prog, &inps);
-        e.write_traces("./test.txt").unwrap()
-    }
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let corpus = corpus(default_programs(), &inps, 20000, 500, 12, &mut rng);
+    let examples = corpus
+        .iter()
+        .map(|(prog, inps)| Examples::new(prog, inps))
+        .collect::<Vec<_>>();
+    println!("generated {} programs", examples.len());
+
+    train_weights(&examples).unwrap().save("data/weights.txt").unwrap();
+
+    // which programs were intended, for `SynthConfig::ranking`
+    let tasks = examples.iter().map(|e| e.ranking_task(2, 10)).collect::<Vec<_>>();
+    let budget = Budget::default().timeout(std::time::Duration::from_secs(1));
+    let config = SynthConfig::default().budget(budget);
+    let (model, skipped) = RankModel::fit(&tasks, &config, 10, 0.1);
+    println!("skipped {skipped} of {} tasks when fitting the ranking", tasks.len());
+    model.save("data/ranking.txt").unwrap();
+
+    // what the programs have in common, for `SynthConfig::library`
+    let programs = tasks.iter().map(|task| task.program.clone()).collect::<Vec<_>>();
+    Library::learn(&programs, 20).save("data/library.txt").unwrap();
 }
+
@@ -13,7 +13,50 @@ pub use top_down::top_down;
 mod combo;
 pub use combo::combo_synth;
 
+mod budget;
+pub use budget::{Budget, CancelToken, Exhausted, SynthError};
+
+mod config;
+pub use config::{Hints, SynthConfig};
+
+mod grammar;
+pub use grammar::{Arg, Grammar, GrammarError, Kind, Nonterminal, Rule};
+
+mod weights;
+pub use weights::Weights;
+
+mod absint;
+pub use absint::{Abstract, CharSet};
+
+mod mcmc;
+pub use mcmc::{duet_mcmc, Mcmc};
+
+mod library;
+pub use library::{Abstraction, Library};
+
+mod ranking;
+pub use ranking::{features, RankModel, RankingTask};
+
+mod sketch;
+pub use sketch::parse_sketch;
+
+mod spec;
+pub use spec::Spec;
+
+mod engine;
+pub use engine::{Dsl, DslAST, DslVSA, Goal, Witness};
+
+mod session;
+pub use session::{Cegis, CegisError, DuetSession, Noisy};
+
 mod top_down_vsa;
 pub use top_down_vsa::regex;
+pub use top_down_vsa::{StringDsl, SynthSession};
 pub use top_down_vsa::top_down as duet;
+pub use top_down_vsa::top_down_with_budget as duet_with_budget;
+pub use top_down_vsa::top_down_noisy as duet_noisy;
+pub use top_down_vsa::top_down_cegis as duet_cegis;
+pub use top_down_vsa::top_down_with_negatives as duet_with_negatives;
+pub use top_down_vsa::top_down_with_specs as duet_with_specs;
+pub use top_down_vsa::complete_sketch;
 pub use top_down_vsa::top_down_vsa;
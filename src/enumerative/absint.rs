@@ -0,0 +1,160 @@
+use crate::vsa::{Fun, Lit, Type};
+
+use super::mcmc::type_of;
+
+type AST = crate::vsa::AST<Lit, Fun>;
+
+// A set of characters, exact for ASCII and with one bit for everything else
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CharSet {
+    ascii: u128,
+    other: bool,
+}
+
+impl CharSet {
+    pub const ALL: CharSet = CharSet {
+        ascii: u128::MAX,
+        other: true,
+    };
+
+    pub fn of(s: &str) -> Self {
+        s.chars().fold(CharSet::default(), |set, c| set.union(CharSet::char(c)))
+    }
+
+    fn char(c: char) -> Self {
+        if c.is_ascii() {
+            CharSet {
+                ascii: 1u128 << c as u32,
+                other: false,
+            }
+        } else {
+            CharSet {
+                ascii: 0,
+                other: true,
+            }
+        }
+    }
+
+    pub fn union(self, other: CharSet) -> Self {
+        CharSet {
+            ascii: self.ascii | other.ascii,
+            other: self.other || other.other,
+        }
+    }
+
+    pub fn intersects(self, other: CharSet) -> bool {
+        self.ascii & other.ascii != 0 || (self.other && other.other)
+    }
+
+    pub fn is_subset(self, other: CharSet) -> bool {
+        self.ascii & !other.ascii == 0 && (!self.other || other.other)
+    }
+
+    // Non-ASCII letters can map to anything, which is already in `other`
+    fn map_ascii(self, from: std::ops::RangeInclusive<u8>, to: u8) -> Self {
+        let mut ascii = self.ascii;
+        for c in from.clone() {
+            if ascii & (1u128 << c) != 0 {
+                ascii &= !(1u128 << c);
+                ascii |= 1u128 << (c - from.start() + to);
+            }
+        }
+        CharSet { ascii, ..self }
+    }
+
+    pub fn lowercase(self) -> Self {
+        self.map_ascii(b'A'..=b'Z', b'a')
+    }
+
+    pub fn uppercase(self) -> Self {
+        self.map_ascii(b'a'..=b'z', b'A')
+    }
+}
+
+// What a string-valued program could output on one input: a length
+// interval in bytes and the characters it could have. Witnesses are exact
+// already, so this only prunes where nothing exact is cheap: `CharSet` keeps
+// `learn` from splitting a Concat goal into two pieces that can only be
+// constants, and `Dsl::may_output` rules out sketches that can't produce an
+// example's output before learning it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Abstract {
+    pub min_len: usize,
+    pub max_len: Option<usize>,
+    pub chars: CharSet,
+}
+
+impl Abstract {
+    pub const TOP: Abstract = Abstract {
+        min_len: 0,
+        max_len: None,
+        chars: CharSet::ALL,
+    };
+
+    pub fn exact(s: &str) -> Self {
+        Abstract {
+            min_len: s.len(),
+            max_len: Some(s.len()),
+            chars: CharSet::of(s),
+        }
+    }
+
+    // what any slice of `inp` could be
+    pub fn substrings(inp: &str) -> Self {
+        Abstract {
+            min_len: 0,
+            max_len: Some(inp.len()),
+            chars: CharSet::of(inp),
+        }
+    }
+
+    pub fn concat(self, other: Abstract) -> Self {
+        Abstract {
+            min_len: self.min_len + other.min_len,
+            max_len: self.max_len.zip(other.max_len).map(|(a, b)| a + b),
+            chars: self.chars.union(other.chars),
+        }
+    }
+
+    pub fn admits(&self, s: &str) -> bool {
+        s.len() >= self.min_len
+            && self.max_len.is_none_or(|max| s.len() <= max)
+            && CharSet::of(s).is_subset(self.chars)
+    }
+
+    // Abstractly evaluates a program that can have holes, or None if it
+    // doesn't output a string. Only the parts with holes are approximated.
+    pub fn eval(ast: &AST, inp: &Lit) -> Option<Abstract> {
+        if !ast.has_holes() {
+            return match ast.eval(inp) {
+                Lit::StringConst(s) => Some(Abstract::exact(&s)),
+                _ => None,
+            };
+        }
+
+        match ast {
+            AST::App { fun: Fun::Concat, args } => {
+                let arg = |i: usize| Abstract::eval(&args[i], inp).unwrap_or(Abstract::TOP);
+                Some(arg(0).concat(arg(1)))
+            }
+            AST::App { fun: Fun::Slice, .. } => match inp {
+                Lit::StringConst(inp) => Some(Abstract::substrings(inp)),
+                _ => Some(Abstract::TOP),
+            },
+            // case mapping can change the length of non-ASCII text
+            AST::App {
+                fun: fun @ (Fun::Lowercase | Fun::Uppercase),
+                args,
+            } => {
+                let chars = Abstract::eval(&args[0], inp).map_or(CharSet::ALL, |arg| arg.chars);
+                let chars = match fun {
+                    Fun::Lowercase => chars.lowercase(),
+                    _ => chars.uppercase(),
+                };
+                Some(Abstract { chars, ..Abstract::TOP })
+            }
+            _ if type_of(ast) == Type::String => Some(Abstract::TOP),
+            _ => None,
+        }
+    }
+}
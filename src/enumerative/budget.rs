@@ -0,0 +1,211 @@
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::vsa::{Fun, Lit};
+
+type AST = crate::vsa::AST<Lit, Fun>;
+
+// Limits for a single synthesis call. Everything except `max_size` and
+// `max_rejected` is unbounded by default.
+//
+// Note that `Instant::now` panics on wasm32-unknown-unknown, so the front end
+// should cancel through a `CancelToken` instead of setting a deadline.
+#[derive(Debug, Clone)]
+pub struct Budget {
+    // absolute, so every session given the budget shares it
+    pub deadline: Option<Instant>,
+    // starts over for every session the budget is given to
+    pub timeout: Option<Duration>,
+    pub max_size: usize,
+    pub max_bank_entries: Option<usize>,
+    pub max_vsa_nodes: Option<usize>,
+    // how many programs can be picked and then turned down for a negative
+    // example, a partial one or the sketch, since each one is taken out of
+    // the version space on its own
+    pub max_rejected: usize,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget {
+            deadline: None,
+            timeout: None,
+            max_size: 6,
+            max_bank_entries: None,
+            max_vsa_nodes: None,
+            max_rejected: 1000,
+        }
+    }
+}
+
+impl Budget {
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // The timeout turned into a deadline from now, or the deadline if that's
+    // sooner
+    pub(crate) fn armed(&self) -> Budget {
+        let mut budget = self.clone();
+        if let Some(timeout) = budget.timeout.take() {
+            let end = Instant::now() + timeout;
+            budget.deadline = Some(self.deadline.map_or(end, |deadline| deadline.min(end)));
+        }
+        budget
+    }
+
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn max_bank_entries(mut self, max_bank_entries: usize) -> Self {
+        self.max_bank_entries = Some(max_bank_entries);
+        self
+    }
+
+    pub fn max_vsa_nodes(mut self, max_vsa_nodes: usize) -> Self {
+        self.max_vsa_nodes = Some(max_vsa_nodes);
+        self
+    }
+
+    pub fn max_rejected(mut self, max_rejected: usize) -> Self {
+        self.max_rejected = max_rejected;
+        self
+    }
+}
+
+// Shared flag that lets another thread (or a JS callback) stop a running
+// synthesis call. `bottom_up` and `learn` check it as they go.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exhausted {
+    Deadline,
+    Cancelled,
+    MaxSize,
+    BankEntries,
+    VsaNodes,
+    Rejected,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SynthError<P = AST> {
+    NoExamples,
+    // `best` is the best program consistent with the examples that had been
+    // intersected when the budget ran out, if there was one
+    Timeout {
+        reason: Exhausted,
+        best: Option<P>,
+    },
+}
+
+impl<P: std::fmt::Display> std::fmt::Display for SynthError<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SynthError::NoExamples => write!(f, "no examples"),
+            SynthError::Timeout { reason, best: None } => write!(f, "{reason:?}, nothing found"),
+            SynthError::Timeout {
+                reason,
+                best: Some(best),
+            } => write!(f, "{reason:?}, best so far: {best}"),
+        }
+    }
+}
+
+impl<P: std::fmt::Debug + std::fmt::Display> std::error::Error for SynthError<P> {}
+
+// Keeps track of how much of a `Budget` one synthesis call has used
+pub(crate) struct Meter<'a> {
+    budget: &'a Budget,
+    cancel: &'a CancelToken,
+    vsa_nodes: Cell<usize>,
+    rejected: Cell<usize>,
+    exhausted: Cell<Option<Exhausted>>,
+}
+
+impl<'a> Meter<'a> {
+    pub fn new(budget: &'a Budget, cancel: &'a CancelToken) -> Self {
+        Meter {
+            budget,
+            cancel,
+            vsa_nodes: Cell::new(0),
+            rejected: Cell::new(0),
+            exhausted: Cell::new(None),
+        }
+    }
+
+    pub fn exhausted(&self) -> Option<Exhausted> {
+        if self.exhausted.get().is_none() {
+            if self.cancel.is_cancelled() {
+                self.exhausted.set(Some(Exhausted::Cancelled));
+            } else if self.budget.deadline.is_some_and(|d| Instant::now() >= d) {
+                self.exhausted.set(Some(Exhausted::Deadline));
+            }
+        }
+
+        self.exhausted.get()
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted().is_some()
+    }
+
+    pub fn add_vsa_nodes(&self, n: usize) {
+        self.vsa_nodes.set(self.vsa_nodes.get() + n);
+        if self
+            .budget
+            .max_vsa_nodes
+            .is_some_and(|max| self.vsa_nodes.get() > max)
+        {
+            self.exhaust(Exhausted::VsaNodes);
+        }
+    }
+
+    pub fn add_rejected(&self) {
+        self.rejected.set(self.rejected.get() + 1);
+        if self.rejected.get() > self.budget.max_rejected {
+            self.exhaust(Exhausted::Rejected);
+        }
+    }
+
+    pub fn check_bank_entries(&self, entries: usize) -> bool {
+        if self.budget.max_bank_entries.is_some_and(|max| entries > max) {
+            self.exhaust(Exhausted::BankEntries);
+        }
+
+        !self.is_exhausted()
+    }
+
+    fn exhaust(&self, reason: Exhausted) {
+        if self.exhausted.get().is_none() {
+            self.exhausted.set(Some(reason));
+        }
+    }
+}
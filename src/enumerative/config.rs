@@ -0,0 +1,355 @@
+use std::collections::HashSet;
+
+use wasm_bindgen::prelude::*;
+
+use crate::vsa::{Cost, Fun, Lit};
+
+use super::{
+    grammar::{Grammar, Kind},
+    Budget, Library, Mcmc, RankModel, Weights,
+};
+
+type AST = crate::vsa::AST<Lit, Fun>;
+
+// Ranks are whole numbers, so bits are rounded to a tenth
+const BITS_SCALE: f64 = 10.0;
+
+// Things the user expects to matter for a task. Hinted constants and regexes
+// are seeded into the banks even when they aren't shared by every example,
+// and anything hinted is ranked as cheaper than it normally would be.
+#[derive(Debug, Clone, Default)]
+pub struct Hints {
+    pub constants: Vec<String>,
+    pub regexes: Vec<String>,
+    pub funs: Vec<Fun>,
+}
+
+// Everything about the search that used to be hard-coded in `top_down_vsa.rs`.
+// The defaults reproduce the original behaviour.
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct SynthConfig {
+    pub(crate) funs: HashSet<Fun>,
+    pub(crate) constants: Vec<Lit>,
+    pub(crate) regexes: Vec<String>,
+    pub(crate) budget: Budget,
+    pub(crate) enumerate_loc_arith: bool,
+    pub(crate) enumerate_concats: bool,
+    pub(crate) enumerate_equal: bool,
+    pub(crate) hints: Hints,
+    // replaces the hard-coded enumeration when set
+    pub(crate) grammar: Option<Grammar>,
+    pub(crate) max_cost: Option<usize>,
+    // orders the search by likelihood instead of only by size
+    pub(crate) weights: Option<Weights>,
+    // replaces `rank` when set
+    pub(crate) ranking: Option<RankModel>,
+    // for `duet_noisy`
+    pub(crate) max_wrong: usize,
+    pub(crate) wrong_cost: usize,
+    // for `duet_mcmc`
+    pub(crate) mcmc: Mcmc,
+    // how many rows `duet_cegis` starts with
+    pub(crate) cegis_initial: usize,
+}
+
+impl Default for SynthConfig {
+    fn default() -> Self {
+        SynthConfig {
+            funs: Fun::ALL
+                .into_iter()
+                .filter(|fun| fun != &Fun::ConcatMap)
+                .collect(),
+            constants: vec![
+                Lit::Input,
+                Lit::StringConst("".to_string()),
+                Lit::StringConst(" ".to_string()),
+                Lit::StringConst(".".to_string()),
+                Lit::LocConst(0),
+                Lit::LocConst(1),
+                Lit::LocEnd,
+            ],
+            regexes: ["\\d", "\\b", "[a-z]", "[A-Z]"]
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            budget: Budget::default(),
+            enumerate_loc_arith: false,
+            enumerate_concats: false,
+            enumerate_equal: true,
+            hints: Hints::default(),
+            grammar: None,
+            max_cost: None,
+            weights: None,
+            ranking: None,
+            max_wrong: 0,
+            wrong_cost: 10,
+            mcmc: Mcmc::default(),
+            cegis_initial: 3,
+        }
+    }
+}
+
+impl SynthConfig {
+    pub fn funs(mut self, funs: impl IntoIterator<Item = Fun>) -> Self {
+        self.funs = funs.into_iter().collect();
+        self
+    }
+
+    pub fn enable(mut self, fun: Fun) -> Self {
+        self.funs.insert(fun);
+        self
+    }
+
+    pub fn disable(mut self, fun: Fun) -> Self {
+        self.funs.remove(&fun);
+        self
+    }
+
+    pub fn constants(mut self, constants: impl IntoIterator<Item = Lit>) -> Self {
+        self.constants = constants.into_iter().collect();
+        self
+    }
+
+    pub fn constant(mut self, constant: Lit) -> Self {
+        self.constants.push(constant);
+        self
+    }
+
+    pub fn regexes(mut self, regexes: impl IntoIterator<Item = String>) -> Self {
+        self.regexes = regexes.into_iter().collect();
+        self
+    }
+
+    pub fn regex(mut self, regex: impl Into<String>) -> Self {
+        self.regexes.push(regex.into());
+        self
+    }
+
+    pub fn budget(mut self, budget: Budget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.budget.max_size = max_size;
+        self
+    }
+
+    // Programs that cost more than this are pruned from the version space
+    // after every intersection (by `Cost::cost`, not `rank`)
+    pub fn max_cost(mut self, max_cost: usize) -> Self {
+        self.max_cost = Some(max_cost);
+        self
+    }
+
+    // Candidates of the same size are enumerated most likely first, so the
+    // program kept for each set of outputs is the likeliest one, and the
+    // program picked out of the version space is the likeliest one. Like a
+    // ranking model, this ignores the hints.
+    pub fn weights(mut self, weights: Weights) -> Self {
+        self.weights = Some(weights);
+        self
+    }
+
+    // Enables every function in the library, on top of whatever's enabled
+    // already
+    pub fn library(mut self, library: &Library) -> Self {
+        self.funs.extend(library.funs());
+        self
+    }
+
+    // A learned ranking, which ignores the hints
+    pub fn ranking(mut self, ranking: RankModel) -> Self {
+        self.ranking = Some(ranking);
+        self
+    }
+
+    // How many examples `duet_noisy` can treat as typos
+    pub fn max_wrong(mut self, max_wrong: usize) -> Self {
+        self.max_wrong = max_wrong;
+        self
+    }
+
+    // What a wrong example adds to the rank of a program in `duet_noisy`, so
+    // that a bigger program that fits everything can still win
+    pub fn wrong_cost(mut self, wrong_cost: usize) -> Self {
+        self.wrong_cost = wrong_cost;
+        self
+    }
+
+    pub fn mcmc(mut self, mcmc: Mcmc) -> Self {
+        self.mcmc = mcmc;
+        self
+    }
+
+    // At most this many rows with different shapes are given to `duet_cegis`
+    // up front, the rest only when they turn out to be needed
+    pub fn cegis_initial(mut self, cegis_initial: usize) -> Self {
+        self.cegis_initial = cegis_initial;
+        self
+    }
+
+    // LocAdd and LocSub are only ever built bottom up
+    pub fn enumerate_loc_arith(mut self, enable: bool) -> Self {
+        self.enumerate_loc_arith = enable;
+        self
+    }
+
+    // the concat witness should make these redundant
+    pub fn enumerate_concats(mut self, enable: bool) -> Self {
+        self.enumerate_concats = enable;
+        self
+    }
+
+    // Equal is still only enumerated when one of the outputs is a bool
+    pub fn enumerate_equal(mut self, enable: bool) -> Self {
+        self.enumerate_equal = enable;
+        self
+    }
+
+    // The operators, seeds and costs all come from the grammar, everything
+    // else (budget, hints) is kept. Even data/strings.grammar, which has the
+    // default operators and seeds, only gives the same bank up to 4 nodes:
+    // the hard-coded enumeration counts sizes differently, only concatenates
+    // seed regexes and never passes a FindEnd as an argument, so the two can
+    // find different programs.
+    pub fn grammar(mut self, grammar: Grammar) -> Self {
+        self.funs = grammar.funs().collect();
+        self.constants = grammar
+            .seeds
+            .iter()
+            .filter(|(nt, _)| grammar.nonterminals[*nt].kind != Kind::Regex)
+            .map(|(_, lit)| lit.clone())
+            .collect();
+        self.regexes = grammar
+            .seeds_of(Kind::Regex)
+            .filter_map(|lit| match lit {
+                Lit::StringConst(s) => Some(s.clone()),
+                _ => None,
+            })
+            .collect();
+        self.funs.extend(self.hints.funs.iter().copied());
+        self.grammar = Some(grammar);
+        self
+    }
+
+    pub fn hints(mut self, hints: Hints) -> Self {
+        self.funs.extend(hints.funs.iter().copied());
+        self.hints = hints;
+        self
+    }
+
+    pub fn hint_constant(mut self, constant: impl Into<String>) -> Self {
+        self.hints.constants.push(constant.into());
+        self
+    }
+
+    pub fn hint_regex(mut self, regex: impl Into<String>) -> Self {
+        self.hints.regexes.push(regex.into());
+        self
+    }
+
+    pub fn hint_fun(mut self, fun: Fun) -> Self {
+        self.funs.insert(fun);
+        self.hints.funs.push(fun);
+        self
+    }
+
+    // what actually gets seeded into the bank, on top of the shared characters
+    pub(crate) fn seed_constants(&self) -> impl Iterator<Item = Lit> + '_ {
+        self.constants.iter().cloned().chain(
+            self.hints
+                .constants
+                .iter()
+                .map(|c| Lit::StringConst(c.clone())),
+        )
+    }
+
+    // hinted constants are also searched for literally
+    pub(crate) fn seed_regexes(&self) -> impl Iterator<Item = Lit> + '_ {
+        self.regexes
+            .iter()
+            .chain(self.hints.regexes.iter())
+            .cloned()
+            .chain(self.hints.constants.iter().map(|c| regex::escape(c)))
+            .map(Lit::StringConst)
+    }
+
+    // `Cost::cost`, except that hinted constants and functions are free, or
+    // whatever the ranking model says if there is one, or else the bits of
+    // the program under the weights
+    pub fn rank(&self, ast: &AST) -> usize {
+        if let Some(ranking) = &self.ranking {
+            return ranking.rank(ast);
+        }
+        if let Some(weights) = &self.weights {
+            return (weights.bits(ast) * BITS_SCALE).round() as usize;
+        }
+
+        match ast {
+            AST::Lit(l) if self.is_hinted(l) => 0,
+            AST::Lit(l) => l.cost(),
+            AST::Hole(_) => 1,
+            AST::App { fun, args } => {
+                self.fun_rank(*fun) + args.iter().map(|arg| self.hinted_size(arg)).sum::<usize>()
+            }
+        }
+    }
+
+    // Whether `rank` is plain `Cost::cost`, with nothing free
+    pub(crate) fn ranks_by_cost(&self) -> bool {
+        self.ranking.is_none()
+            && self.weights.is_none()
+            && self.hints.constants.is_empty()
+            && self.hints.regexes.is_empty()
+            && self.hints.funs.is_empty()
+    }
+
+    fn hinted_size(&self, ast: &AST) -> usize {
+        match ast {
+            AST::Lit(l) if self.is_hinted(l) => 0,
+            AST::Lit(_) | AST::Hole(_) => 1,
+            AST::App { fun, args } => {
+                usize::from(!self.hints.funs.contains(fun))
+                    + args.iter().map(|arg| self.hinted_size(arg)).sum::<usize>()
+            }
+        }
+    }
+
+    fn fun_rank(&self, fun: Fun) -> usize {
+        let cost = self
+            .grammar
+            .as_ref()
+            .and_then(|grammar| grammar.cost(fun))
+            .unwrap_or_else(|| fun.cost());
+
+        if self.hints.funs.contains(&fun) {
+            cost.saturating_sub(1)
+        } else {
+            cost
+        }
+    }
+
+    fn is_hinted(&self, lit: &Lit) -> bool {
+        match lit {
+            Lit::StringConst(s) => {
+                self.hints.constants.contains(s) || self.hints.regexes.contains(s)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_enabled(&self, fun: Fun) -> bool {
+        self.funs.contains(&fun)
+    }
+
+    pub fn enumerates(&self, fun: Fun) -> bool {
+        self.is_enabled(fun)
+            && match fun {
+                Fun::LocAdd | Fun::LocSub => self.enumerate_loc_arith,
+                Fun::Equal => self.enumerate_equal,
+                _ => true,
+            }
+    }
+}
@@ -0,0 +1,488 @@
+use std::{
+    cell::Cell,
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    hash::Hash,
+    rc::Rc,
+};
+
+use crate::{
+    bank::Bank,
+    vsa::{
+        arena::{Arena, VsaId},
+        InputLit, Language, AST, VSA,
+    },
+};
+
+use itertools::Itertools;
+
+use super::budget::Meter;
+
+pub type DslAST<D> = AST<<D as Dsl>::L, <D as Dsl>::F>;
+pub type DslVSA<D> = VSA<<D as Dsl>::L, <D as Dsl>::F>;
+
+// One argument of a witness
+pub enum Goal<D: Dsl> {
+    // any program that outputs this on the current input
+    Learn(D::L),
+    // exactly these programs, e.g. everything in the bank of the right type
+    Fixed(Rc<DslVSA<D>>),
+    // a nested application, so that witnesses can describe more than one
+    // level of the program at a time
+    Join(D::F, Vec<Goal<D>>),
+}
+
+impl<D: Dsl> Clone for Goal<D> {
+    fn clone(&self) -> Self {
+        match self {
+            Goal::Learn(out) => Goal::Learn(out.clone()),
+            Goal::Fixed(vsa) => Goal::Fixed(vsa.clone()),
+            Goal::Join(op, goals) => Goal::Join(*op, goals.clone()),
+        }
+    }
+}
+
+// Inverse semantics for a function symbol
+pub trait Witness<D: Dsl> {
+    // every way `self` could output `out` on `inp`, as one goal per argument
+    fn witness(&self, inp: &D::L, out: &D::L, dsl: &D, bank: &Bank<DslAST<D>>)
+        -> Vec<Vec<Goal<D>>>;
+}
+
+// Everything the duet engine needs to know about a language. `L` are the
+// values and literals, `F` are the function symbols.
+pub trait Dsl: Sized {
+    type L: Clone + Eq + Hash + Debug + InputLit;
+    type F: Language<Self::L> + Witness<Self> + Eq + Copy + Hash + Debug;
+    // An output that's only partly known, like a prefix or a length. Languages
+    // without any can use `std::convert::Infallible`.
+    type Partial: Clone + Debug;
+
+    // Whether `seeds` would give something different for these examples,
+    // in which case the banks have to be rebuilt
+    fn seeds_changed(&self, examples: &[(Self::L, Self::L)]) -> bool;
+
+    // the literals the bank starts out with
+    fn seeds(&mut self, examples: &[(Self::L, Self::L)]) -> Vec<Self::L>;
+
+    // the function symbols `learn` tries the witnesses of, in order
+    fn funs(&self) -> Vec<Self::F>;
+
+    // programs without a function symbol at the top that output `out`
+    fn lit_witness(&self, inp: &Self::L, out: &Self::L) -> Vec<DslAST<Self>>;
+
+    // candidate programs of exactly `size`, built out of the smaller ones
+    // in `bank`
+    fn enumerate<'a>(
+        &'a self,
+        size: usize,
+        bank: &'a Bank<DslAST<Self>>,
+    ) -> Box<dyn Iterator<Item = DslAST<Self>> + 'a>;
+
+    // lower is better
+    fn rank(&self, ast: &DslAST<Self>) -> usize;
+
+    // A lower bound on the rank of anything that needs a bank of `size` to
+    // be found, so `synthesize_noisy` knows when growing the bank can't turn
+    // up anything better. By default nothing is ruled out.
+    fn min_rank(&self, _size: usize) -> usize {
+        0
+    }
+
+    fn satisfies(&self, partial: &Self::Partial, out: &Self::L) -> bool;
+
+    // Whether a program with holes could output `out` on `inp` at all, so
+    // `learn_sketch` doesn't run the witnesses down sketches that can't. By
+    // default anything could.
+    fn may_output(&self, _inp: &Self::L, _sketch: &DslAST<Self>, _out: &Self::L) -> bool {
+        true
+    }
+
+    // Like a witness, but for a partial output and with the function symbol
+    // picked by the language. The bank entries that satisfy it are always
+    // included, so by default there's nothing else.
+    fn partial_witness(
+        &self,
+        _inp: &Self::L,
+        _partial: &Self::Partial,
+        _bank: &Bank<DslAST<Self>>,
+    ) -> Vec<(Self::F, Vec<Goal<Self>>)> {
+        Vec::new()
+    }
+
+    // Runs after every intersection. The arena already drops dead branches
+    // and merges identical nodes as it goes, so by default there's nothing
+    // left to do.
+    fn minimize(&self, _arena: &mut Arena<Self::L, Self::F>, id: VsaId) -> VsaId {
+        id
+    }
+}
+
+// How many more times the goals on a cycle get rebuilt with each other's
+// VSAs, i.e. how many times a program can go around the cycle
+const CYCLE_UNROLLING: usize = 2;
+
+// A witness argument with every `Goal::Learn` swapped for the index of that
+// goal in the table
+enum Arg<D: Dsl> {
+    Goal(usize),
+    Fixed(Rc<DslVSA<D>>),
+    Join(D::F, Vec<Arg<D>>),
+}
+
+// everything that can output one goal
+struct Entry<D: Dsl> {
+    // bank entries and literals
+    base: Rc<DslVSA<D>>,
+    joins: Vec<(D::F, Vec<Arg<D>>)>,
+}
+
+struct Table<D: Dsl> {
+    index: HashMap<D::L, usize>,
+    outs: Vec<D::L>,
+    entries: Vec<Option<Entry<D>>>,
+    worklist: VecDeque<usize>,
+}
+
+impl<D: Dsl> Table<D> {
+    fn new() -> Self {
+        Table {
+            index: HashMap::new(),
+            outs: Vec::new(),
+            entries: Vec::new(),
+            worklist: VecDeque::new(),
+        }
+    }
+
+    fn goal(&mut self, out: D::L) -> usize {
+        if let Some(i) = self.index.get(&out) {
+            return *i;
+        }
+
+        self.index.insert(out.clone(), self.outs.len());
+        self.outs.push(out);
+        self.entries.push(None);
+        self.worklist.push_back(self.outs.len() - 1);
+        self.outs.len() - 1
+    }
+
+    fn arg(&mut self, goal: Goal<D>) -> Arg<D> {
+        match goal {
+            Goal::Learn(out) => Arg::Goal(self.goal(out)),
+            Goal::Fixed(vsa) => Arg::Fixed(vsa),
+            Goal::Join(op, goals) => Arg::Join(op, goals.into_iter().map(|g| self.arg(g)).collect()),
+        }
+    }
+}
+
+// Learns every output the witnesses lead to exactly once. The goals are first
+// collected with a worklist into a table, and then built children first. A
+// goal that leads back to itself is built with only its base programs the
+// first time around, and then `CYCLE_UNROLLING` more times with what the
+// goals on the cycle learned the time before.
+pub(super) fn learn<D: Dsl>(
+    dsl: &D,
+    inp: &D::L,
+    out: &D::L,
+    cache: &mut HashMap<D::L, Rc<DslVSA<D>>>,
+    bank: &Bank<DslAST<D>>,
+    meter: &Meter,
+) -> Rc<DslVSA<D>> {
+    let mut table = Table::new();
+    let root = table.goal(out.clone());
+
+    match solve(dsl, inp, table, cache, bank, meter) {
+        Some((_, mut vsas)) => vsas[root].take().unwrap(),
+        None => Rc::new(VSA::empty()),
+    }
+}
+
+// Everything in `cache` whose output satisfies `partial`, and whatever
+// `Dsl::partial_witness` leads to, learned like `learn` does
+pub(super) fn learn_partial<D: Dsl>(
+    dsl: &D,
+    inp: &D::L,
+    partial: &D::Partial,
+    cache: &mut HashMap<D::L, Rc<DslVSA<D>>>,
+    bank: &Bank<DslAST<D>>,
+    meter: &Meter,
+) -> Rc<DslVSA<D>> {
+    let base = cache
+        .iter()
+        .filter(|(out, _)| dsl.satisfies(partial, out))
+        .map(|(_, vsa)| vsa.clone())
+        .fold(Rc::new(VSA::empty()), |acc, x| Rc::new(VSA::unify(acc, x)));
+
+    let mut table = Table::new();
+    let joins = dsl
+        .partial_witness(inp, partial, bank)
+        .into_iter()
+        .map(|(fun, args)| (fun, args.into_iter().map(|goal| table.arg(goal)).collect::<Vec<_>>()))
+        .collect::<Vec<_>>();
+
+    let Some((entries, vsas)) = solve(dsl, inp, table, cache, bank, meter) else {
+        return Rc::new(VSA::empty());
+    };
+
+    joins
+        .iter()
+        .map(|(op, args)| {
+            Rc::new(VSA::Join {
+                op: *op,
+                children: args.iter().map(|arg| build(arg, &entries, &vsas)).collect(),
+            })
+        })
+        .fold(base, |acc, x| Rc::new(VSA::unify(acc, x)))
+}
+
+// Completions of `sketch` that output `out`, learned like `learn` does. The
+// witnesses are run down the sketch to find what each hole has to output, so
+// a hole under a function without a witness (or one that's only given a
+// `Goal::Fixed`) can't be filled.
+pub(super) fn learn_sketch<D: Dsl>(
+    dsl: &D,
+    inp: &D::L,
+    out: &D::L,
+    sketch: &DslAST<D>,
+    cache: &mut HashMap<D::L, Rc<DslVSA<D>>>,
+    bank: &Bank<DslAST<D>>,
+    meter: &Meter,
+) -> Rc<DslVSA<D>> {
+    let mut table = Table::new();
+    let args = sketch_goals(dsl, inp, out, sketch, bank)
+        .into_iter()
+        .map(|goal| table.arg(goal))
+        .collect::<Vec<_>>();
+
+    let Some((entries, vsas)) = solve(dsl, inp, table, cache, bank, meter) else {
+        return Rc::new(VSA::empty());
+    };
+
+    args.iter()
+        .map(|arg| build(arg, &entries, &vsas))
+        .fold(Rc::new(VSA::empty()), |acc, x| Rc::new(VSA::unify(acc, x)))
+}
+
+// every way `sketch` could output `out` on `inp`, with the holes as
+// `Goal::Learn`s and everything else fixed
+fn sketch_goals<D: Dsl>(
+    dsl: &D,
+    inp: &D::L,
+    out: &D::L,
+    sketch: &DslAST<D>,
+    bank: &Bank<DslAST<D>>,
+) -> Vec<Goal<D>> {
+    match sketch {
+        AST::Hole(_) => vec![Goal::Learn(out.clone())],
+        AST::App { .. } if sketch.has_holes() && !dsl.may_output(inp, sketch, out) => Vec::new(),
+        AST::App { fun, args } if sketch.has_holes() => fun
+            .witness(inp, out, dsl, bank)
+            .into_iter()
+            .flat_map(|goals| {
+                args.iter()
+                    .zip(goals)
+                    .map(|(arg, goal)| refine(dsl, inp, arg, goal, bank))
+                    .multi_cartesian_product()
+                    .map(|args| Goal::Join(*fun, args))
+                    .collect::<Vec<_>>()
+            })
+            .collect(),
+        _ if sketch.eval(inp) == *out => vec![Goal::Fixed(Rc::new(VSA::singleton(sketch.clone())))],
+        _ => Vec::new(),
+    }
+}
+
+// narrows what a witness wants of an argument down to what fits the sketch
+fn refine<D: Dsl>(
+    dsl: &D,
+    inp: &D::L,
+    sketch: &DslAST<D>,
+    goal: Goal<D>,
+    bank: &Bank<DslAST<D>>,
+) -> Vec<Goal<D>> {
+    match (sketch, goal) {
+        (AST::Hole(_), goal) => vec![goal],
+        (_, Goal::Learn(out)) => sketch_goals(dsl, inp, &out, sketch, bank),
+        (_, Goal::Fixed(vsa)) if !sketch.has_holes() && vsa.contains(sketch) => {
+            vec![Goal::Fixed(Rc::new(VSA::singleton(sketch.clone())))]
+        }
+        (AST::App { fun, args }, Goal::Join(op, goals)) if *fun == op && args.len() == goals.len() => args
+            .iter()
+            .zip(goals)
+            .map(|(arg, goal)| refine(dsl, inp, arg, goal, bank))
+            .multi_cartesian_product()
+            .map(|args| Goal::Join(op, args))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+type Solved<D> = (Vec<Entry<D>>, Vec<Option<Rc<DslVSA<D>>>>);
+
+// Works through the goals in `table` and builds every one of them, or gives
+// up with None once the meter runs out
+fn solve<D: Dsl>(
+    dsl: &D,
+    inp: &D::L,
+    mut table: Table<D>,
+    cache: &mut HashMap<D::L, Rc<DslVSA<D>>>,
+    bank: &Bank<DslAST<D>>,
+    meter: &Meter,
+) -> Option<Solved<D>> {
+    while let Some(i) = table.worklist.pop_front() {
+        // whatever was learned so far gets thrown away by the caller anyway
+        if meter.is_exhausted() {
+            return None;
+        }
+
+        let out = table.outs[i].clone();
+        let base = cache
+            .get(&out)
+            .cloned()
+            .into_iter()
+            .chain(
+                dsl.lit_witness(inp, &out)
+                    .into_iter()
+                    .map(|ast| Rc::new(VSA::singleton(ast))),
+            )
+            .fold(Rc::new(VSA::empty()), |acc, x| Rc::new(VSA::unify(acc, x)));
+
+        let mut joins = Vec::new();
+        for fun in dsl.funs() {
+            for args in fun.witness(inp, &out, dsl, bank) {
+                joins.push((fun, args.into_iter().map(|goal| table.arg(goal)).collect()));
+            }
+        }
+
+        meter.add_vsa_nodes(1 + joins.len());
+        table.entries[i] = Some(Entry { base, joins });
+    }
+
+    let entries = table
+        .entries
+        .into_iter()
+        .map(Option::unwrap)
+        .collect::<Vec<_>>();
+
+    let mut order = Vec::new();
+    let mut cyclic = false;
+    let mut visits = vec![Visit::New; entries.len()];
+    for i in 0..entries.len() {
+        if visits[i] == Visit::New {
+            postorder(&entries, i, &mut visits, &mut order, &mut cyclic);
+        }
+    }
+
+    let mut vsas = vec![None; entries.len()];
+    let passes = if cyclic { 1 + CYCLE_UNROLLING } else { 1 };
+    for _ in 0..passes {
+        for i in order.iter() {
+            let entry = &entries[*i];
+            let vsa = entry
+                .joins
+                .iter()
+                .map(|(op, args)| {
+                    Rc::new(VSA::Join {
+                        op: *op,
+                        children: args.iter().map(|arg| build(arg, &entries, &vsas)).collect(),
+                    })
+                })
+                .fold(entry.base.clone(), |acc, x| Rc::new(VSA::unify(acc, x)));
+            vsas[*i] = Some(vsa);
+        }
+    }
+
+    Some((entries, vsas))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Visit {
+    New,
+    InProgress,
+    Done,
+}
+
+fn postorder<D: Dsl>(
+    entries: &[Entry<D>],
+    i: usize,
+    visits: &mut [Visit],
+    order: &mut Vec<usize>,
+    cyclic: &mut bool,
+) {
+    visits[i] = Visit::InProgress;
+
+    fn goals<D: Dsl>(arg: &Arg<D>, out: &mut Vec<usize>) {
+        match arg {
+            Arg::Goal(j) => out.push(*j),
+            Arg::Fixed(_) => {}
+            Arg::Join(_, args) => args.iter().for_each(|arg| goals(arg, out)),
+        }
+    }
+
+    let mut children = Vec::new();
+    for (_, args) in entries[i].joins.iter() {
+        args.iter().for_each(|arg| goals(arg, &mut children));
+    }
+
+    for j in children {
+        match visits[j] {
+            Visit::New => postorder(entries, j, visits, order, cyclic),
+            Visit::InProgress => *cyclic = true,
+            Visit::Done => {}
+        }
+    }
+
+    visits[i] = Visit::Done;
+    order.push(i);
+}
+
+// goals that haven't been built yet are on a cycle with the one being built
+fn build<D: Dsl>(arg: &Arg<D>, entries: &[Entry<D>], vsas: &[Option<Rc<DslVSA<D>>>]) -> Rc<DslVSA<D>> {
+    match arg {
+        Arg::Goal(j) => vsas[*j].clone().unwrap_or_else(|| entries[*j].base.clone()),
+        Arg::Fixed(vsa) => vsa.clone(),
+        Arg::Join(op, args) => Rc::new(VSA::Join {
+            op: *op,
+            children: args.iter().map(|arg| build(arg, entries, vsas)).collect(),
+        }),
+    }
+}
+
+// Adds every new program of `size` to the bank, where new means that no other
+// program gives the same outputs on the examples
+pub(super) fn bottom_up<D: Dsl>(
+    dsl: &D,
+    inputs: &[D::L],
+    size: usize,
+    cache: &mut HashMap<Vec<D::L>, Rc<DslVSA<D>>>,
+    bank: &mut Bank<DslAST<D>>,
+    meter: &Meter,
+) -> bool {
+    bank.grow_to(size);
+    let entries = bank.total_entries();
+    let added = Cell::new(0);
+
+    let adjs: Vec<DslAST<D>> = dsl
+        .enumerate(size, bank)
+        .take_while(|_| meter.check_bank_entries(entries + added.get()))
+        .filter(|adj| {
+            let outs = inputs
+                .iter()
+                .map(|inp| adj.eval(inp))
+                .collect::<Vec<_>>();
+            use std::collections::hash_map::Entry;
+
+            if let Entry::Vacant(e) = cache.entry(outs) {
+                e.insert(Rc::new(VSA::singleton(adj.clone())));
+                added.set(added.get() + 1);
+                true
+            } else {
+                false
+            }
+        })
+        .collect();
+
+    bank.size_mut(size).extend(adjs);
+
+    !meter.is_exhausted()
+}
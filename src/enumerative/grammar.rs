@@ -0,0 +1,388 @@
+use std::str::FromStr;
+
+use itertools::Itertools;
+
+use crate::{
+    bank::Bank,
+    vsa::{Fun, Lit, Type},
+};
+
+use super::top_down_vsa::{arg_tuples, compositions};
+
+type AST = crate::vsa::AST<Lit, Fun>;
+
+// A description of the DSL in a small text format, e.g.
+//
+//     # comments start with a hash
+//     nonterminal S : string
+//     nonterminal L : loc
+//     nonterminal R : regex
+//
+//     S := Slice(L, L) | Concat(S, S) cost 2 witness_only
+//     S := Concat(S, "+")
+//     L := Find(S, R, L) | FindEnd(S, R, L)
+//
+//     S := X | "" | " "
+//     L := 0 | 1 | $
+//     R := "\d" | "[a-z]"
+//
+// Operators are the builtin `Fun`s or functions registered through
+// `vsa::custom`, since their semantics and witnesses can't be written down
+// here. Literals are `X` for the input, `$` for the end of the input, numbers
+// for locations, `true`/`false`, and anything between double quotes (taken
+// verbatim, so regexes don't need escaping). An argument can also be a
+// literal, which is always that literal.
+//
+// `cost` overrides the cost of the operator when ranking, and `witness_only`
+// rules are only ever built top down by their witness.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grammar {
+    pub nonterminals: Vec<Nonterminal>,
+    pub rules: Vec<Rule>,
+    // the nonterminal each seed belongs to
+    pub seeds: Vec<(usize, Lit)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nonterminal {
+    pub name: String,
+    pub kind: Kind,
+}
+
+// Regexes are strings as far as `eval` is concerned, but they are kept in a
+// bank of their own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    String,
+    Regex,
+    Loc,
+    Bool,
+}
+
+impl Kind {
+    pub fn ty(self) -> Type {
+        match self {
+            Kind::String | Kind::Regex => Type::String,
+            Kind::Loc => Type::Loc,
+            Kind::Bool => Type::Bool,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub ret: usize,
+    pub fun: Fun,
+    pub args: Vec<Arg>,
+    pub cost: Option<usize>,
+    pub enumerate: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Arg {
+    Nonterminal(usize),
+    // counts as size 1, like the seeds
+    Lit(Lit),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrammarError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl std::fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl std::error::Error for GrammarError {}
+
+impl FromStr for Grammar {
+    type Err = GrammarError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut grammar = Grammar {
+            nonterminals: Vec::new(),
+            rules: Vec::new(),
+            seeds: Vec::new(),
+        };
+
+        for (i, line) in s.lines().enumerate() {
+            let err = |msg: String| GrammarError { line: i + 1, msg };
+            let line = strip_comment(line).trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(decl) = line.strip_prefix("nonterminal ") {
+                let (name, kind) = decl
+                    .split_once(':')
+                    .ok_or_else(|| err("expected `nonterminal NAME : KIND`".to_string()))?;
+                let name = name.trim();
+                if grammar.nonterminal(name).is_some() {
+                    return Err(err(format!("{name} is declared twice")));
+                }
+                let kind = match kind.trim() {
+                    "string" => Kind::String,
+                    "regex" => Kind::Regex,
+                    "loc" => Kind::Loc,
+                    "bool" => Kind::Bool,
+                    kind => return Err(err(format!("unknown kind {kind}"))),
+                };
+                grammar.nonterminals.push(Nonterminal {
+                    name: name.to_string(),
+                    kind,
+                });
+                continue;
+            }
+
+            let (lhs, rhs) = line
+                .split_once(":=")
+                .ok_or_else(|| err(format!("expected a declaration or a rule, got {line}")))?;
+            let ret = grammar
+                .nonterminal(lhs.trim())
+                .ok_or_else(|| err(format!("undeclared nonterminal {}", lhs.trim())))?;
+
+            for alt in split_outside_quotes(rhs, '|') {
+                grammar.parse_alternative(ret, alt.trim()).map_err(err)?;
+            }
+        }
+
+        Ok(grammar)
+    }
+}
+
+impl Grammar {
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(std::fs::read_to_string(path)?.parse()?)
+    }
+
+    pub fn nonterminal(&self, name: &str) -> Option<usize> {
+        self.nonterminals.iter().position(|nt| nt.name == name)
+    }
+
+    pub fn funs(&self) -> impl Iterator<Item = Fun> + '_ {
+        self.rules.iter().map(|rule| rule.fun).unique()
+    }
+
+    // the first rule with an explicit cost wins
+    pub fn cost(&self, fun: Fun) -> Option<usize> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.fun == fun)
+            .find_map(|rule| rule.cost)
+    }
+
+    pub fn seeds_of(&self, kind: Kind) -> impl Iterator<Item = &Lit> + '_ {
+        self.seeds
+            .iter()
+            .filter(move |(nt, _)| self.nonterminals[*nt].kind == kind)
+            .map(|(_, lit)| lit)
+    }
+
+    // Since the bank doesn't remember which rule built an entry, an
+    // application belongs to every nonterminal that has a rule for its operator
+    fn belongs(&self, ast: &AST, nt: usize) -> bool {
+        match ast {
+            AST::Lit(Lit::Input) => self.nonterminals[nt].kind == Kind::String,
+            AST::Lit(l) => {
+                let kind = self.nonterminals[nt].kind;
+                kind != Kind::Regex && l.ty() == kind.ty()
+            }
+            AST::App { fun, .. } => self
+                .rules
+                .iter()
+                .any(|rule| rule.ret == nt && rule.fun == *fun),
+            AST::Hole(_) => false,
+        }
+    }
+
+    fn of_size<'a>(
+        &'a self,
+        nt: usize,
+        n: usize,
+        bank: &'a Bank<AST>,
+        regex_bank: &'a Bank<AST>,
+    ) -> impl Iterator<Item = &'a AST> + 'a {
+        let regexes = match self.nonterminals[nt].kind {
+            Kind::Regex => regex_bank.entries.get(n - 1).map(Vec::as_slice),
+            _ => None,
+        };
+
+        bank.size(n)
+            .iter()
+            .filter(move |ast| self.belongs(ast, nt))
+            .chain(regexes.into_iter().flatten())
+    }
+
+    // every application of size `size` the rules allow, with bool rules only
+    // when the outputs are bools
+    pub(crate) fn enumerate<'a>(
+        &'a self,
+        size: usize,
+        bank: &'a Bank<AST>,
+        regex_bank: &'a Bank<AST>,
+        enable_bools: bool,
+    ) -> impl Iterator<Item = AST> + 'a {
+        self.rules
+            .iter()
+            .filter(move |rule| {
+                rule.enumerate && (enable_bools || self.nonterminals[rule.ret].kind != Kind::Bool)
+            })
+            .flat_map(move |rule| {
+                // a call without arguments is one node, but the seeds are
+                // the only programs of size 1
+                let arg_sizes = match rule.args.len() {
+                    0 if size == 2 => vec![Vec::new()],
+                    0 => Vec::new(),
+                    n => compositions(size - 1, n),
+                };
+                arg_sizes
+                    .into_iter()
+                    .flat_map(move |sizes| {
+                        let options = rule
+                            .args
+                            .iter()
+                            .zip(sizes)
+                            .map(|(arg, n)| match arg {
+                                Arg::Nonterminal(nt) => self
+                                    .of_size(*nt, n, bank, regex_bank)
+                                    .cloned()
+                                    .collect::<Vec<_>>(),
+                                Arg::Lit(lit) if n == 1 => vec![AST::Lit(lit.clone())],
+                                Arg::Lit(_) => Vec::new(),
+                            })
+                            .collect();
+                        arg_tuples(options).map(move |args| AST::App { fun: rule.fun, args })
+                    })
+            })
+    }
+
+    fn parse_alternative(&mut self, ret: usize, alt: &str) -> Result<(), String> {
+        if alt.is_empty() {
+            return Err("empty alternative".to_string());
+        }
+
+        match alt.find('(') {
+            Some(open) if !alt.starts_with('"') => {
+                let close = alt
+                    .rfind(')')
+                    .ok_or_else(|| format!("missing ) in {alt}"))?;
+                let fun = Fun::from_str(alt[..open].trim())?;
+                let args = split_outside_quotes(&alt[open + 1..close], ',')
+                    .into_iter()
+                    .map(str::trim)
+                    .filter(|arg| !arg.is_empty())
+                    .map(|arg| match self.nonterminal(arg) {
+                        Some(nt) => Ok(Arg::Nonterminal(nt)),
+                        None => parse_lit(arg)
+                            .map(Arg::Lit)
+                            .map_err(|_| format!("undeclared nonterminal {arg}")),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let mut cost = None;
+                let mut enumerate = true;
+                let mut flags = alt[close + 1..].split_whitespace();
+                while let Some(flag) = flags.next() {
+                    match flag {
+                        "cost" => {
+                            let n = flags.next().ok_or("missing cost")?;
+                            cost = Some(n.parse().map_err(|_| format!("bad cost {n}"))?);
+                        }
+                        "witness_only" => enumerate = false,
+                        flag => return Err(format!("unknown flag {flag}")),
+                    }
+                }
+
+                let arg_types = args
+                    .iter()
+                    .map(|arg| match arg {
+                        Arg::Nonterminal(nt) => self.nonterminals[*nt].kind.ty(),
+                        Arg::Lit(lit) => lit.ty(),
+                    })
+                    .collect::<Vec<_>>();
+                if arg_types != fun.args() {
+                    return Err(format!(
+                        "{} takes {:?}, got {:?}",
+                        fun.name(),
+                        fun.args(),
+                        arg_types
+                    ));
+                }
+                if fun.ret() != self.nonterminals[ret].kind.ty() {
+                    return Err(format!(
+                        "{} returns a {:?}, not a {}",
+                        fun.name(),
+                        fun.ret(),
+                        self.nonterminals[ret].name
+                    ));
+                }
+
+                self.rules.push(Rule {
+                    ret,
+                    fun,
+                    args,
+                    cost,
+                    enumerate,
+                });
+            }
+            _ => {
+                let lit = parse_lit(alt)?;
+                if lit.ty() != self.nonterminals[ret].kind.ty() {
+                    return Err(format!("{alt} is not a {}", self.nonterminals[ret].name));
+                }
+                self.seeds.push((ret, lit));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(super) fn parse_lit(s: &str) -> Result<Lit, String> {
+    match s {
+        "X" => Ok(Lit::Input),
+        "$" => Ok(Lit::LocEnd),
+        "true" => Ok(Lit::BoolConst(true)),
+        "false" => Ok(Lit::BoolConst(false)),
+        _ if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') => {
+            Ok(Lit::StringConst(s[1..s.len() - 1].to_string()))
+        }
+        _ => s
+            .parse()
+            .map(Lit::LocConst)
+            .map_err(|_| format!("can't parse {s}")),
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_quotes = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '#' if !in_quotes => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn split_outside_quotes(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == sep && !in_quotes {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
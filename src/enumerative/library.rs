@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use itertools::Itertools;
+
+use crate::vsa::{
+    custom::{self, CustomFun},
+    Fun, Lit,
+};
+
+use super::{grammar::GrammarError, mcmc::type_of, sketch::parse_sketch};
+
+type AST = crate::vsa::AST<Lit, Fun>;
+
+// Patterns with more nodes than this aren't looked for
+const MAX_FIXED: usize = 8;
+
+// Every parameter is another argument the bank has to enumerate
+const MAX_PARAMS: usize = 3;
+
+// A program with holes that's been registered as a custom function, with
+// the holes as its parameters from left to right
+#[derive(Debug, Clone)]
+pub struct Abstraction {
+    pub fun: Fun,
+    pub body: AST,
+}
+
+impl Abstraction {
+    // Bodies can use `X` and `Slice`, so they're evaluated against the input
+    // the call is evaluated on. A call costs 1 like any other function, so
+    // the bank has it as soon as it has the arguments. Without parameters
+    // there's nothing to search for, so `learn` can also use it directly.
+    // Defining a name that's registered already replaces it, so a library
+    // can be loaded again after it's been edited.
+    fn define(name: &str, body: AST) -> Result<Self, String> {
+        let def = body.clone();
+        let mut fun = CustomFun::with_input(name, body.holes(), type_of(&body), move |args, inp| {
+            fill(&def, &mut args.iter()).eval(inp)
+        });
+        if !body.has_holes() {
+            let def = body.clone();
+            fun = fun.witness(move |inp, out| {
+                if def.eval(inp) == *out {
+                    vec![vec![]]
+                } else {
+                    vec![]
+                }
+            });
+        }
+
+        let fun = match custom::lookup(name) {
+            Some(_) => custom::replace(fun)?,
+            None => custom::register(fun)?,
+        };
+        Ok(Abstraction { fun, body })
+    }
+
+    // every application of the body replaced with a call, outermost first
+    fn rewrite(&self, ast: &AST) -> AST {
+        let mut bound = Vec::new();
+        if matches(&self.body, ast, &mut bound) {
+            return AST::App {
+                fun: self.fun,
+                args: bound.iter().map(|arg| self.rewrite(arg)).collect(),
+            };
+        }
+
+        match ast {
+            AST::App { fun, args } => AST::App {
+                fun: *fun,
+                args: args.iter().map(|arg| self.rewrite(arg)).collect(),
+            },
+            _ => ast.clone(),
+        }
+    }
+}
+
+// Subprograms that keep showing up in solved tasks, as new primitives. In
+// text, one `name := body` per line with the body written like a sketch:
+//
+//     # after the first space
+//     rest := Slice(FindEnd(X, ' ', 0), $)
+//     shout := Concat(Uppercase(?), '!')
+//
+// Bodies can call anything defined above them. Parsing a library registers
+// every function in it, and `SynthConfig::library` enables them.
+#[derive(Debug, Clone, Default)]
+pub struct Library {
+    abstractions: Vec<Abstraction>,
+}
+
+impl Library {
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(std::fs::read_to_string(path)?.parse()?)
+    }
+
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_string())
+    }
+
+    pub fn abstractions(&self) -> &[Abstraction] {
+        &self.abstractions
+    }
+
+    pub fn funs(&self) -> impl Iterator<Item = Fun> + '_ {
+        self.abstractions.iter().map(|a| a.fun)
+    }
+
+    // A greedy compression pass: every pattern of up to `MAX_FIXED` nodes
+    // that matches a subprogram of the corpus is counted, and the one that
+    // would shrink the corpus the most, net of its own size, becomes a
+    // function and replaces its matches. That repeats with the rewritten
+    // corpus, so later functions can be built out of earlier ones, until
+    // nothing saves anything or there are `max_size` functions. Whatever's
+    // learned is named `lib0`, `lib1`, ... and registered.
+    pub fn learn(programs: &[AST], max_size: usize) -> Library {
+        let mut corpus = programs.to_vec();
+        let mut library = Library::default();
+
+        while library.abstractions.len() < max_size {
+            let Some(body) = best_pattern(&corpus) else {
+                break;
+            };
+
+            let name = (0..)
+                .map(|i| format!("lib{i}"))
+                .find(|name| custom::lookup(name).is_none())
+                .unwrap();
+            let abstraction = Abstraction::define(&name, body).unwrap();
+            corpus = corpus.iter().map(|p| abstraction.rewrite(p)).collect();
+            library.abstractions.push(abstraction);
+        }
+
+        library
+    }
+
+    // `ast` with every function in the library used wherever it fits
+    pub fn rewrite(&self, ast: &AST) -> AST {
+        self.abstractions
+            .iter()
+            .fold(ast.clone(), |ast, abstraction| abstraction.rewrite(&ast))
+    }
+}
+
+// the pattern that saves the most nodes, ties broken by how it's written so
+// learning doesn't depend on hashing
+fn best_pattern(corpus: &[AST]) -> Option<AST> {
+    let mut uses: HashMap<String, (AST, usize, usize)> = HashMap::new();
+    for ast in corpus.iter().flat_map(subtrees).filter(|ast| abstractable(ast)) {
+        for (pattern, fixed) in fragments(ast, MAX_FIXED) {
+            if fixed < 2 || pattern.holes().len() > MAX_PARAMS {
+                continue;
+            }
+            uses.entry(call_syntax(&pattern))
+                .or_insert((pattern, fixed, 0))
+                .2 += 1;
+        }
+    }
+
+    // each use turns `fixed` nodes into one, and the definition is paid for
+    // once
+    uses.into_iter()
+        .filter_map(|(text, (pattern, fixed, n))| {
+            let saved = (n * (fixed - 1)).checked_sub(fixed).filter(|saved| *saved > 0)?;
+            Some((saved, text, pattern))
+        })
+        .max_by(|a, b| a.0.cmp(&b.0).then_with(|| b.1.cmp(&a.1)))
+        .map(|(_, _, pattern)| pattern)
+}
+
+fn subtrees(ast: &AST) -> Vec<&AST> {
+    let mut all = vec![ast];
+    if let AST::App { args, .. } = ast {
+        all.extend(args.iter().flat_map(subtrees));
+    }
+    all
+}
+
+// ConcatMap evaluates its second argument on parts of the input, so it
+// isn't taken apart, and strings with both kinds of quotes can't be saved
+fn abstractable(ast: &AST) -> bool {
+    match ast {
+        AST::App { fun: Fun::ConcatMap, .. } | AST::Hole(_) => false,
+        AST::App { args, .. } => args.iter().all(abstractable),
+        AST::Lit(Lit::StringConst(s)) => !(s.contains('\'') && s.contains('"')),
+        AST::Lit(_) => true,
+    }
+}
+
+// Every pattern with at most `budget` nodes that matches `ast` at its root,
+// with how many of its nodes aren't holes. Every hole is a different
+// parameter.
+fn fragments(ast: &AST, budget: usize) -> Vec<(AST, usize)> {
+    if budget == 0 {
+        return Vec::new();
+    }
+    let AST::App { fun, args } = ast else {
+        return vec![(ast.clone(), 1)];
+    };
+
+    let mut partial = vec![(Vec::new(), 1)];
+    for arg in args {
+        let options = std::iter::once((AST::Hole(type_of(arg)), 0))
+            .chain(fragments(arg, budget - 1))
+            .collect::<Vec<_>>();
+
+        partial = partial
+            .into_iter()
+            .flat_map(|(done, fixed)| {
+                options
+                    .iter()
+                    .filter(move |(_, n)| fixed + n <= budget)
+                    .map(move |(option, n)| {
+                        let mut done: Vec<AST> = done.clone();
+                        done.push(option.clone());
+                        (done, fixed + n)
+                    })
+            })
+            .collect();
+    }
+
+    partial
+        .into_iter()
+        .map(|(args, fixed)| (AST::App { fun: *fun, args }, fixed))
+        .collect()
+}
+
+// the holes of `pattern` are pushed onto `bound` in order
+fn matches(pattern: &AST, ast: &AST, bound: &mut Vec<AST>) -> bool {
+    match (pattern, ast) {
+        (AST::Hole(_), _) => {
+            bound.push(ast.clone());
+            true
+        }
+        (AST::App { fun: f, args: ps }, AST::App { fun: g, args }) => {
+            f == g && ps.iter().zip(args).all(|(p, arg)| matches(p, arg, bound))
+        }
+        _ => pattern == ast,
+    }
+}
+
+fn fill<'a>(body: &AST, args: &mut impl Iterator<Item = &'a Lit>) -> AST {
+    match body {
+        AST::Hole(_) => AST::Lit(args.next().unwrap().clone()),
+        AST::App { fun, args: body_args } => AST::App {
+            fun: *fun,
+            args: body_args.iter().map(|arg| fill(arg, args)).collect(),
+        },
+        AST::Lit(_) => body.clone(),
+    }
+}
+
+// what `parse_sketch` reads, unlike `Display`
+fn call_syntax(ast: &AST) -> String {
+    match ast {
+        AST::App { fun, args } => {
+            format!("{}({})", fun.name(), args.iter().map(call_syntax).join(", "))
+        }
+        AST::Lit(Lit::StringConst(s)) if s.contains('\'') => format!("\"{s}\""),
+        AST::Lit(Lit::StringConst(s)) => format!("'{s}'"),
+        AST::Lit(lit) => lit.to_string(),
+        AST::Hole(_) => "?".to_string(),
+    }
+}
+
+impl FromStr for Library {
+    type Err = GrammarError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut library = Library::default();
+
+        for (i, line) in s.lines().enumerate() {
+            let err = |msg: String| GrammarError { line: i + 1, msg };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (name, body) = line
+                .split_once(":=")
+                .ok_or_else(|| err(format!("expected `name := body`, got {line}")))?;
+            let name = name.trim();
+            if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                return Err(err(format!("bad name {name}")));
+            }
+            if Fun::ALL.iter().any(|fun| fun.name() == name) {
+                return Err(err(format!("{name} is already a builtin")));
+            }
+            if library.abstractions.iter().any(|a| a.fun.name() == name) {
+                return Err(err(format!("{name} is defined twice")));
+            }
+
+            let body = parse_sketch(body.trim()).map_err(err)?;
+            if !matches!(body, AST::App { .. }) {
+                return Err(err(format!("the body of {name} has to call something")));
+            }
+            library.abstractions.push(Abstraction::define(name, body).map_err(err)?);
+        }
+
+        Ok(library)
+    }
+}
+
+impl std::fmt::Display for Library {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for abstraction in self.abstractions.iter() {
+            writeln!(f, "{} := {}", abstraction.fun.name(), call_syntax(&abstraction.body))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,389 @@
+use itertools::Itertools;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::vsa::{Fun, Lit, Type};
+
+use super::{top_down_vsa::shared_chars, CancelToken, SynthConfig, SynthError};
+
+type AST = crate::vsa::AST<Lit, Fun>;
+
+// Settings for `duet_mcmc`. The defaults always give the same run for the
+// same examples.
+#[derive(Debug, Clone)]
+pub struct Mcmc {
+    pub steps: usize,
+    // how much less likely a move is accepted for each unit it makes the score
+    // worse
+    pub beta: f64,
+    // What every node adds to the score, so programs don't grow without
+    // bound. There are many more big programs than small ones, so with
+    // `beta` this has to outweigh how much likelier it is to propose a wrap
+    // in a Concat than to undo it, or the search just keeps growing.
+    pub size_cost: f64,
+    // bigger programs are never moved to, which keeps every step cheap
+    pub max_size: usize,
+    pub seed: u64,
+}
+
+impl Default for Mcmc {
+    fn default() -> Self {
+        Mcmc {
+            steps: 20_000,
+            beta: 2.0,
+            size_cost: 1.0,
+            max_size: 40,
+            seed: 0,
+        }
+    }
+}
+
+// Runs `duet` within the budget, and if it doesn't find anything, a
+// Metropolis-Hastings search starting from the best program it had so far
+// (or just `X`). That search scores a program by the edit distance between
+// its outputs and the examples', plus `size_cost` per node, and keeps
+// replacing a random subtree with a random program of the same type,
+// wrapping it in a Concat, or replacing it with one of its own arguments.
+// A move is accepted with probability `exp(-beta * change in score)` times
+// how much more likely the move back is to be proposed than the move itself,
+// so moves that can't be undone are never taken.
+//
+// The search only stops early for the cancel token, not the deadline, which
+// is usually what stopped `duet` in the first place. If it doesn't find a
+// program that fits every example either, the closest one it saw is the
+// `best` of the error.
+pub fn duet_mcmc(
+    examples: &[(Lit, Lit)],
+    config: &SynthConfig,
+    cancel: &CancelToken,
+) -> Result<AST, SynthError> {
+    let (reason, best) = match super::duet_with_budget(examples, config, cancel) {
+        Err(SynthError::Timeout { reason, best }) => (reason, best),
+        res => return res,
+    };
+
+    let mut search = Search::new(examples, config);
+    let start = best.unwrap_or(AST::Lit(Lit::Input));
+    let (best, distance) = search.run(start, cancel);
+
+    if distance == 0 {
+        Ok(search.simplify(best))
+    } else {
+        Err(SynthError::Timeout {
+            reason,
+            best: Some(best),
+        })
+    }
+}
+
+struct Search<'a> {
+    examples: &'a [(Lit, Lit)],
+    params: &'a Mcmc,
+    funs: Vec<Fun>,
+    lits: Vec<Lit>,
+    rng: StdRng,
+}
+
+impl<'a> Search<'a> {
+    // The same operators and seeds as the bank, so nothing it doesn't
+    // enumerate either. Custom functions are left out since their semantics
+    // can be anything.
+    fn new(examples: &'a [(Lit, Lit)], config: &'a SynthConfig) -> Self {
+        let funs = Fun::ALL
+            .into_iter()
+            .filter(|fun| config.enumerates(*fun) && *fun != Fun::ConcatMap)
+            .collect();
+        let lits = config
+            .seed_constants()
+            .chain(config.seed_regexes())
+            .chain(shared_chars(examples))
+            .unique()
+            .collect();
+
+        Search {
+            examples,
+            params: &config.mcmc,
+            funs,
+            lits,
+            rng: StdRng::seed_from_u64(config.mcmc.seed),
+        }
+    }
+
+    // the best program and its total edit distance
+    fn run(&mut self, start: AST, cancel: &CancelToken) -> (AST, usize) {
+        let distance = self.distance(&start);
+        let mut score = self.score(&start, distance);
+        let mut current = start;
+        let mut best = (current.clone(), distance);
+
+        for step in 0..self.params.steps {
+            if best.1 == 0 || (step % 256 == 0 && cancel.is_cancelled()) {
+                break;
+            }
+
+            let next = self.mutate(&current);
+            if next.size() > self.params.max_size {
+                continue;
+            }
+            let next_distance = self.distance(&next);
+            let next_score = self.score(&next, next_distance);
+
+            let ratio = (-self.params.beta * (next_score - score)).exp()
+                * self.proposal(&next, &current)
+                / self.proposal(&current, &next);
+            if ratio < 1.0 && self.rng.gen::<f64>() >= ratio {
+                continue;
+            }
+
+            if next_distance < best.1 || (next_distance == best.1 && next.size() < best.0.size()) {
+                best = (next.clone(), next_distance);
+            }
+            (current, score) = (next, next_score);
+        }
+
+        best
+    }
+
+    // The search stops at the first program that fits, which tends to have
+    // leftovers like `Lowercase` around a constant. Anything that still fits
+    // with a node replaced by one of its arguments doesn't need that node.
+    fn simplify(&self, mut program: AST) -> AST {
+        'outer: loop {
+            for i in 0..program.size() {
+                let node = nth(&program, i);
+                let AST::App { args, .. } = node else {
+                    continue;
+                };
+
+                for arg in args.iter().filter(|arg| type_of(arg) == type_of(node)) {
+                    let candidate = replace_nth(&program, i, arg.clone());
+                    if self.distance(&candidate) == 0 {
+                        program = candidate;
+                        continue 'outer;
+                    }
+                }
+            }
+            return program;
+        }
+    }
+
+    fn score(&self, program: &AST, distance: usize) -> f64 {
+        distance as f64 + self.params.size_cost * program.size() as f64
+    }
+
+    fn distance(&self, program: &AST) -> usize {
+        self.examples
+            .iter()
+            .map(|(inp, out)| match (program.eval(inp), out) {
+                (Lit::StringConst(a), Lit::StringConst(b)) => edit_distance(&a, b),
+                (a, b) if a == *b => 0,
+                (_, Lit::StringConst(b)) => b.chars().count() + 1,
+                _ => 1,
+            })
+            .sum()
+    }
+
+    fn mutate(&mut self, program: &AST) -> AST {
+        let i = self.rng.gen_range(0..program.size());
+        let node = nth(program, i);
+        let ty = type_of(node);
+
+        let replacement = match self.rng.gen_range(0..3) {
+            1 if ty == Type::String && self.funs.contains(&Fun::Concat) => {
+                let other = self.random_program(Type::String, 1);
+                let args = if self.rng.gen_bool(0.5) {
+                    vec![node.clone(), other]
+                } else {
+                    vec![other, node.clone()]
+                };
+                AST::App {
+                    fun: Fun::Concat,
+                    args,
+                }
+            }
+            2 => match node {
+                AST::App { args, .. } => {
+                    let same = args.iter().filter(|arg| type_of(arg) == ty).collect::<Vec<_>>();
+                    match same.choose(&mut self.rng) {
+                        Some(arg) => (*arg).clone(),
+                        None => self.random_program(ty, 2),
+                    }
+                }
+                _ => self.random_program(ty, 2),
+            },
+            _ => self.random_program(ty, 2),
+        };
+
+        replace_nth(program, i, replacement)
+    }
+
+    // How likely `mutate` is to turn `from` into `to`. The replaced node has
+    // the same preorder number in both, so it's any node where the two only
+    // differ below it.
+    fn proposal(&self, from: &AST, to: &AST) -> f64 {
+        let p: f64 = (0..from.size().min(to.size()))
+            .filter(|i| replace_nth(from, *i, nth(to, *i).clone()) == *to)
+            .map(|i| self.move_probability(nth(from, i), nth(to, i)))
+            .sum();
+        p / from.size() as f64
+    }
+
+    // how likely `mutate` is to replace `node` with `replacement`, once it's
+    // picked `node`
+    fn move_probability(&self, node: &AST, replacement: &AST) -> f64 {
+        let ty = type_of(node);
+        let mut regrow = 1.0 / 3.0;
+        let mut p = 0.0;
+
+        if ty == Type::String && self.funs.contains(&Fun::Concat) {
+            if let AST::App { fun: Fun::Concat, args } = replacement {
+                if args[0] == *node {
+                    p += 1.0 / 6.0 * self.generation_probability(&args[1], Type::String, 1);
+                }
+                if args[1] == *node {
+                    p += 1.0 / 6.0 * self.generation_probability(&args[0], Type::String, 1);
+                }
+            }
+        } else {
+            regrow += 1.0 / 3.0;
+        }
+
+        let same = match node {
+            AST::App { args, .. } => args.iter().filter(|arg| type_of(arg) == ty).collect(),
+            _ => Vec::new(),
+        };
+        if same.is_empty() {
+            regrow += 1.0 / 3.0;
+        } else {
+            let n = same.iter().filter(|arg| **arg == replacement).count();
+            p += 1.0 / 3.0 * n as f64 / same.len() as f64;
+        }
+
+        p + regrow * self.generation_probability(replacement, ty, 2)
+    }
+
+    // how likely `random_program(ty, depth)` is to give `ast`
+    fn generation_probability(&self, ast: &AST, ty: Type, depth: usize) -> f64 {
+        let funs = self.funs.iter().filter(|fun| fun.ret() == ty).count();
+        let p_fun = if depth > 0 && funs > 0 { 0.5 } else { 0.0 };
+
+        match ast {
+            AST::App { fun, args } if p_fun > 0.0 && self.funs.contains(fun) && fun.ret() == ty => {
+                let args = args
+                    .iter()
+                    .zip(fun.args())
+                    .map(|(arg, ty)| self.generation_probability(arg, ty, depth - 1))
+                    .product::<f64>();
+                p_fun / funs as f64 * args
+            }
+            AST::Lit(lit) => {
+                let lits = self.lits.iter().filter(|lit| lit.ty() == ty).collect::<Vec<_>>();
+                let p_lit = if lits.is_empty() {
+                    f64::from(*lit == default_lit(ty))
+                } else {
+                    lits.iter().filter(|l| **l == lit).count() as f64 / lits.len() as f64
+                };
+                (1.0 - p_fun) * p_lit
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn random_program(&mut self, ty: Type, depth: usize) -> AST {
+        let funs = self
+            .funs
+            .iter()
+            .filter(|fun| fun.ret() == ty)
+            .copied()
+            .collect::<Vec<_>>();
+
+        if depth > 0 && !funs.is_empty() && self.rng.gen_bool(0.5) {
+            let fun = *funs.choose(&mut self.rng).unwrap();
+            let args = fun
+                .args()
+                .into_iter()
+                .map(|ty| self.random_program(ty, depth - 1))
+                .collect();
+            return AST::App { fun, args };
+        }
+
+        let lits = self.lits.iter().filter(|lit| lit.ty() == ty).collect::<Vec<_>>();
+        match lits.choose(&mut self.rng) {
+            Some(lit) => AST::Lit((*lit).clone()),
+            None => AST::Lit(default_lit(ty)),
+        }
+    }
+}
+
+// what `random_program` falls back on without any literals of a type
+fn default_lit(ty: Type) -> Lit {
+    match ty {
+        Type::String => Lit::Input,
+        Type::Loc => Lit::LocConst(0),
+        Type::Bool => Lit::BoolConst(false),
+    }
+}
+
+pub(super) fn type_of(ast: &AST) -> Type {
+    match ast {
+        AST::App { fun, .. } => fun.ret(),
+        AST::Lit(lit) => lit.ty(),
+        AST::Hole(ty) => *ty,
+    }
+}
+
+// nodes are numbered in preorder
+fn nth(ast: &AST, mut i: usize) -> &AST {
+    if i == 0 {
+        return ast;
+    }
+    i -= 1;
+
+    let AST::App { args, .. } = ast else {
+        unreachable!()
+    };
+    for arg in args {
+        if i < arg.size() {
+            return nth(arg, i);
+        }
+        i -= arg.size();
+    }
+    unreachable!()
+}
+
+fn replace_nth(ast: &AST, mut i: usize, replacement: AST) -> AST {
+    if i == 0 {
+        return replacement;
+    }
+    i -= 1;
+
+    let AST::App { fun, args } = ast else {
+        unreachable!()
+    };
+    let mut args = args.clone();
+    for arg in args.iter_mut() {
+        if i < arg.size() {
+            *arg = replace_nth(arg, i, replacement);
+            break;
+        }
+        i -= arg.size();
+    }
+    AST::App { fun: *fun, args }
+}
+
+// Levenshtein distance over chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let next = (diag + usize::from(ca != *cb)).min(row[j] + 1).min(row[j + 1] + 1);
+            diag = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+
+    row[b.len()]
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use itertools::Itertools;
+
+use crate::vsa::{arena::Arena, Fun, Lit};
+
+use super::{grammar::GrammarError, SynthConfig, SynthSession};
+
+type AST = crate::vsa::AST<Lit, Fun>;
+
+// Ranks are whole numbers, so scores are rounded to a tenth
+const SCALE: f64 = 10.0;
+
+// Keeps every node worth something, so a model can't end up preferring
+// arbitrarily big programs
+const MIN_NODE_WEIGHT: f64 = 0.1;
+
+// A linear ranking function over features of a program, lower is better.
+// Every feature is a count over the nodes of the program that only looks at
+// the node itself, not where it is, so the rank of a program is the sum of
+// the ranks of its parts and `pick_best` can still pick every argument
+// separately. In text, one `feature weight` per line:
+//
+//     # the default model, which is about `AST::cost`
+//     node 1
+//     fun:Concat 1
+//
+// The features are:
+//
+//     node            every node
+//     fun:NAME        every application of NAME
+//     lit:input       X
+//     const:string    a string constant that isn't a regex class or boundary
+//     const:len       the length of those constants
+//     regex:KIND      a string constant that is, with KIND one of class or
+//                     boundary
+//     pos:KIND        a location, with KIND one of start, const or end
+//
+// Features without a weight don't count. Weights can't be negative, and
+// `node` has to have one of at least `MIN_NODE_WEIGHT`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankModel {
+    weights: HashMap<String, f64>,
+}
+
+impl Default for RankModel {
+    fn default() -> Self {
+        RankModel {
+            weights: [("node", 1.0), ("fun:Concat", 1.0)]
+                .into_iter()
+                .map(|(f, w)| (f.to_string(), w))
+                .collect(),
+        }
+    }
+}
+
+// What a pair generated by `datagen` is turned into: the examples the
+// synthesizer sees, and more inputs to tell whether it found the intended
+// program or just one that fits
+#[derive(Debug, Clone)]
+pub struct RankingTask {
+    pub examples: Vec<(Lit, Lit)>,
+    pub tests: Vec<Lit>,
+    pub program: AST,
+}
+
+impl RankingTask {
+    // whether `program` does what the intended one does on the tests
+    pub fn generalizes(&self, program: &AST) -> bool {
+        self.tests
+            .iter()
+            .all(|inp| program.eval(inp) == self.program.eval(inp))
+    }
+}
+
+impl RankModel {
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(std::fs::read_to_string(path)?.parse()?)
+    }
+
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_string())
+    }
+
+    pub fn weight(&self, feature: &str) -> f64 {
+        self.weights.get(feature).copied().unwrap_or(0.0)
+    }
+
+    pub fn score(&self, ast: &AST) -> f64 {
+        features(ast)
+            .iter()
+            .map(|(feature, count)| self.weight(feature) * count)
+            .sum()
+    }
+
+    pub fn rank(&self, ast: &AST) -> usize {
+        (self.score(ast) * SCALE).round().max(0.0) as usize
+    }
+
+    // A structured perceptron: every task is synthesized once, and then for
+    // every epoch, whenever the best program in its version space under the
+    // current weights doesn't generalize, the weights move towards the
+    // intended program and away from the one that was picked. Tasks that
+    // can't be synthesized within the budget (which every task gets all of),
+    // or whose version space doesn't have the intended program, can't be
+    // fixed by any ranking, so they're skipped. The data usually can't be
+    // separated, so the weights can keep going back and forth, and what's
+    // returned is the model that made the fewest mistakes after any epoch,
+    // which is never worse than the default on these tasks. Returns the
+    // model and how many tasks were skipped.
+    pub fn fit(
+        tasks: &[RankingTask],
+        config: &SynthConfig,
+        epochs: usize,
+        rate: f64,
+    ) -> (RankModel, usize) {
+        let spaces = tasks
+            .iter()
+            .filter_map(|task| {
+                let mut session = SynthSession::with_config(&task.examples, config.clone());
+                session.synthesize().ok()?;
+                let vsa = session.version_space()?;
+
+                let mut arena = Arena::new();
+                let id = arena.import(&vsa);
+                let intended = arena.contains(id, &task.program);
+                Some((task, arena, id, intended))
+            })
+            .collect::<Vec<_>>();
+        let skipped = tasks.len() - spaces.iter().filter(|space| space.3).count();
+
+        // how many tasks a model picks something for that doesn't generalize,
+        // counting the ones without the intended program too, since those can
+        // still be broken
+        let mistakes = |model: &RankModel| {
+            spaces
+                .iter()
+                .filter(|(task, arena, id, _)| {
+                    arena
+                        .pick_best(*id, |ast| model.rank(ast))
+                        .is_some_and(|picked| !task.generalizes(&picked))
+                })
+                .count()
+        };
+
+        let mut model = RankModel::default();
+        let mut best = (mistakes(&model), model.clone());
+        for _ in 0..epochs {
+            if best.0 == 0 {
+                break;
+            }
+
+            for (task, arena, id, _) in spaces.iter().filter(|space| space.3) {
+                let Some(picked) = arena.pick_best(*id, |ast| model.rank(ast)) else {
+                    continue;
+                };
+                if task.generalizes(&picked) {
+                    continue;
+                }
+
+                for (feature, count) in features(&task.program) {
+                    *model.weights.entry(feature).or_insert(0.0) -= rate * count;
+                }
+                for (feature, count) in features(&picked) {
+                    *model.weights.entry(feature).or_insert(0.0) += rate * count;
+                }
+                for (feature, w) in model.weights.iter_mut() {
+                    let min = if feature == "node" { MIN_NODE_WEIGHT } else { 0.0 };
+                    *w = w.max(min);
+                }
+            }
+
+            let now = mistakes(&model);
+            if now < best.0 {
+                best = (now, model.clone());
+            }
+        }
+
+        (best.1, skipped)
+    }
+}
+
+pub fn features(ast: &AST) -> HashMap<String, f64> {
+    let mut features = HashMap::new();
+    add_features(ast, &mut features);
+    features
+}
+
+fn add_features(ast: &AST, features: &mut HashMap<String, f64>) {
+    let mut add = |feature: String, count: f64| *features.entry(feature).or_insert(0.0) += count;
+    add("node".to_string(), 1.0);
+
+    match ast {
+        AST::App { fun, args } => {
+            add(format!("fun:{}", fun.name()), 1.0);
+            for arg in args {
+                add_features(arg, features);
+            }
+        }
+        AST::Lit(Lit::Input) => add("lit:input".to_string(), 1.0),
+        AST::Lit(Lit::StringConst(s)) => match regex_kind(s) {
+            Some(kind) => add(format!("regex:{kind}"), 1.0),
+            None => {
+                add("const:string".to_string(), 1.0);
+                add("const:len".to_string(), s.len() as f64);
+            }
+        },
+        AST::Lit(Lit::LocConst(0)) => add("pos:start".to_string(), 1.0),
+        AST::Lit(Lit::LocConst(_)) => add("pos:const".to_string(), 1.0),
+        AST::Lit(Lit::LocEnd) => add("pos:end".to_string(), 1.0),
+        AST::Lit(Lit::BoolConst(_)) | AST::Hole(_) => {}
+    }
+}
+
+// Only strings that can't mean themselves count as regexes, since whether any
+// other string is used as one depends on where it is
+fn regex_kind(s: &str) -> Option<&'static str> {
+    match s {
+        "\\b" => Some("boundary"),
+        "\\d" | "\\w" | "\\s" => Some("class"),
+        _ if s.len() > 2 && s.starts_with('[') && s.ends_with(']') => Some("class"),
+        _ => None,
+    }
+}
+
+impl FromStr for RankModel {
+    type Err = GrammarError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut weights = HashMap::new();
+
+        for (i, line) in s.lines().enumerate() {
+            let err = |msg: String| GrammarError { line: i + 1, msg };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (feature, weight) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| err(format!("expected `feature weight`, got {line}")))?;
+            let weight = weight
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|w| w.is_finite())
+                .ok_or_else(|| err(format!("bad weight {}", weight.trim())))?;
+            // the same bounds `fit` keeps to
+            if weight < 0.0 {
+                return Err(err(format!("{feature} has a negative weight")));
+            }
+            if feature == "node" && weight < MIN_NODE_WEIGHT {
+                return Err(err(format!("node has to weigh at least {MIN_NODE_WEIGHT}")));
+            }
+            if weights.insert(feature.to_string(), weight).is_some() {
+                return Err(err(format!("{feature} is listed twice")));
+            }
+        }
+
+        if !weights.contains_key("node") {
+            return Err(GrammarError {
+                line: s.lines().count(),
+                msg: "node has no weight".to_string(),
+            });
+        }
+
+        Ok(RankModel { weights })
+    }
+}
+
+impl std::fmt::Display for RankModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (feature, w) in self.weights.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+            writeln!(f, "{feature} {w}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,653 @@
+use std::{collections::HashMap, rc::Rc};
+
+use itertools::Itertools;
+
+use crate::{
+    bank::Bank,
+    vsa::{
+        arena::{Arena, VsaId},
+        AST, VSA,
+    },
+};
+
+use super::{
+    budget::Meter,
+    engine::{bottom_up, learn, learn_partial, learn_sketch, Dsl, DslAST, DslVSA},
+    Budget, CancelToken, Exhausted, SynthError,
+};
+
+// A program that fits every example except the ones in `wrong`, which are
+// indices into the session's examples. Partial examples are never left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Noisy<A> {
+    pub program: A,
+    pub wrong: Vec<usize>,
+}
+
+// What `synthesize_cegis` ended up with. `initial` and `counterexamples` are
+// indices into its rows: the ones it started with, then the ones that were
+// added because the program so far got them wrong, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cegis<A> {
+    pub program: A,
+    pub initial: Vec<usize>,
+    pub counterexamples: Vec<usize>,
+}
+
+// On a timeout, whatever `synthesize_cegis` had when the budget ran out
+pub type CegisError<A> = SynthError<Cegis<Option<A>>>;
+
+// The state of a `top_down` run, kept around so that examples can be added
+// and removed one at a time without redoing the bottom up enumeration.
+//
+// Note that the banks are only deduplicated by their outputs on the examples
+// seen so far, so a session can end up with a different (but still correct)
+// program than a fresh `top_down` run over the same examples.
+pub struct DuetSession<D: Dsl> {
+    dsl: D,
+    examples: Vec<(D::L, D::L)>,
+    // examples whose output is only partly known, which come after
+    // `examples` in `all_cache` and `ex_vsas`
+    partial: Vec<(D::L, D::Partial)>,
+    // (input, output) pairs that the program must not produce
+    negatives: Vec<(D::L, D::L)>,
+    // a program with holes that the result has to fill in
+    sketch: Option<DslAST<D>>,
+
+    bank: Bank<DslAST<D>>,
+    // the outputs of each bank entry on every example
+    all_cache: HashMap<Vec<D::L>, Rc<DslVSA<D>>>,
+    size: usize,
+
+    // one VSA per example, learned with the bank at `size`
+    ex_vsas: Vec<Rc<DslVSA<D>>>,
+    // the intersection of the first `intersected` VSAs in `ex_vsas`, which
+    // lives in `arena` along with everything intersected to get there
+    arena: Arena<D::L, D::F>,
+    res: Option<VsaId>,
+    intersected: usize,
+
+    budget: Budget,
+    cancel: CancelToken,
+    // the program that fits the most examples so far, returned on timeout
+    best: Option<(usize, DslAST<D>)>,
+}
+
+impl<D: Dsl> DuetSession<D> {
+    pub fn from_dsl(dsl: D, examples: &[(D::L, D::L)]) -> Self {
+        let mut session = DuetSession {
+            dsl,
+            examples: examples.to_vec(),
+            partial: Vec::new(),
+            negatives: Vec::new(),
+            sketch: None,
+            bank: Bank::new(),
+            all_cache: HashMap::new(),
+            size: 1,
+            ex_vsas: Vec::new(),
+            arena: Arena::new(),
+            res: None,
+            intersected: 0,
+            budget: Budget::default(),
+            cancel: CancelToken::new(),
+            best: None,
+        };
+        session.reset();
+        session
+    }
+
+    pub fn dsl(&self) -> &D {
+        &self.dsl
+    }
+
+    pub fn examples(&self) -> &[(D::L, D::L)] {
+        &self.examples
+    }
+
+    pub fn partial_examples(&self) -> &[(D::L, D::Partial)] {
+        &self.partial
+    }
+
+    pub fn negatives(&self) -> &[(D::L, D::L)] {
+        &self.negatives
+    }
+
+    // Negative examples don't change the banks or the example VSAs, they're
+    // only checked when picking a program
+    pub fn add_negative(&mut self, inp: D::L, out: D::L) {
+        self.negatives.push((inp, out));
+        self.best = None;
+    }
+
+    // The intersection is built again, so the programs it ruled out can be
+    // picked again
+    pub fn remove_negative(&mut self, i: usize) -> (D::L, D::L) {
+        let removed = self.negatives.remove(i);
+        self.best = None;
+        self.clear_intersection();
+        removed
+    }
+
+    pub fn sketch(&self) -> Option<&DslAST<D>> {
+        self.sketch.as_ref()
+    }
+
+    // With a sketch, `synthesize` only returns programs that fill in its
+    // holes. The banks are kept, but every example has to be learned again.
+    pub fn set_sketch(&mut self, sketch: Option<DslAST<D>>) {
+        self.sketch = sketch;
+        self.best = None;
+        self.ex_vsas.clear();
+        self.clear_intersection();
+    }
+
+    // the VSA learned for each example, as of the last call to `synthesize`
+    pub fn example_vsas(&self) -> &[Rc<DslVSA<D>>] {
+        &self.ex_vsas
+    }
+
+    // The timeout starts now and a deadline is absolute, so the budget has to
+    // be set again before every call to `synthesize` that should get all of
+    // it
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.budget = budget.armed();
+    }
+
+    pub fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.cancel = cancel;
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn add_example(&mut self, inp: D::L, out: D::L) {
+        self.examples.push((inp.clone(), out));
+        self.best = None;
+
+        if self.dsl.seeds_changed(&self.examples) {
+            self.reset();
+            return;
+        }
+
+        // the VSAs of the partial examples aren't used once there's an exact
+        // one, see `learned`
+        let col = self.examples.len() - 1;
+        if self.ex_vsas.len() > col {
+            self.ex_vsas.truncate(col);
+            self.clear_intersection();
+        }
+        self.add_column(col, &inp);
+    }
+
+    pub fn remove_example(&mut self, i: usize) -> (D::L, D::L) {
+        let removed = self.examples.remove(i);
+        self.best = None;
+
+        if self.dsl.seeds_changed(&self.examples) {
+            self.reset();
+            return removed;
+        }
+
+        self.remove_column(i);
+        removed
+    }
+
+    // Partial examples don't change the seeds, since there's no output to
+    // take them from
+    pub fn add_partial(&mut self, inp: D::L, partial: D::Partial) {
+        self.partial.push((inp.clone(), partial));
+        self.best = None;
+        self.add_column(self.columns() - 1, &inp);
+    }
+
+    pub fn remove_partial(&mut self, i: usize) -> (D::L, D::Partial) {
+        let removed = self.partial.remove(i);
+        self.best = None;
+        self.remove_column(self.examples.len() + i);
+        removed
+    }
+
+    pub fn synthesize(&mut self) -> Result<DslAST<D>, SynthError<DslAST<D>>> {
+        if self.columns() == 0 {
+            return Err(SynthError::NoExamples);
+        }
+
+        self.search(|session, meter| session.intersect(meter))
+    }
+
+    // Like `synthesize`, but up to `max_wrong` examples are allowed to be
+    // wrong. Every way of leaving out at most `max_wrong` examples is tried,
+    // and the program that wins is the one with the lowest rank plus
+    // `wrong_cost` for every example it gets wrong. While the best so far
+    // gets something wrong, the bank keeps growing until nothing that needs
+    // the next size could score lower, so a correct program that needs a
+    // bigger bank still beats one that ignores an example. If the budget
+    // runs out first, the best so far is returned.
+    pub fn synthesize_noisy(
+        &mut self,
+        max_wrong: usize,
+        wrong_cost: usize,
+    ) -> Result<Noisy<DslAST<D>>, SynthError<DslAST<D>>> {
+        if max_wrong == 0 {
+            return self.synthesize().map(|program| Noisy {
+                program,
+                wrong: Vec::new(),
+            });
+        }
+
+        if self.columns() == 0 {
+            return Err(SynthError::NoExamples);
+        }
+
+        let mut best: Option<(usize, Noisy<DslAST<D>>)> = None;
+        let res = self.search(|session, meter| {
+            if let Some((score, noisy)) = session.intersect_subsets(max_wrong, wrong_cost, meter) {
+                if best.as_ref().is_none_or(|(best, _)| score < *best) {
+                    best = Some((score, noisy));
+                }
+            }
+
+            let (score, noisy) = best.as_ref()?;
+            if noisy.wrong.is_empty() || session.dsl.min_rank(session.size + 1) >= *score {
+                best.take().map(|(_, noisy)| noisy)
+            } else {
+                None
+            }
+        });
+
+        match (res, best) {
+            (Err(SynthError::Timeout { .. }), Some((_, noisy))) => Ok(noisy),
+            (res, _) => res,
+        }
+    }
+
+    // Counterexample guided synthesis over `rows`: the `initial` rows are added
+    // as examples, and after every synthesis the first row the program gets
+    // wrong is added too, until there's none left. Rows only ever become
+    // examples, so anything that was already in the session stays. If the
+    // budget runs out, the `best` of the error has the rows added so far and
+    // the best program for them, if there was one.
+    pub fn synthesize_cegis(
+        &mut self,
+        rows: &[(D::L, D::L)],
+        initial: &[usize],
+    ) -> Result<Cegis<DslAST<D>>, CegisError<DslAST<D>>> {
+        for i in initial {
+            let (inp, out) = rows[*i].clone();
+            self.add_example(inp, out);
+        }
+
+        let mut counterexamples = Vec::new();
+        loop {
+            let program = match self.synthesize() {
+                Ok(program) => program,
+                Err(SynthError::NoExamples) => return Err(SynthError::NoExamples),
+                Err(SynthError::Timeout { reason, best }) => {
+                    return Err(SynthError::Timeout {
+                        reason,
+                        best: Some(Cegis {
+                            program: best,
+                            initial: initial.to_vec(),
+                            counterexamples,
+                        }),
+                    })
+                }
+            };
+            match rows.iter().position(|(inp, out)| program.eval(inp) != *out) {
+                Some(i) => {
+                    counterexamples.push(i);
+                    let (inp, out) = rows[i].clone();
+                    self.add_example(inp, out);
+                }
+                None => {
+                    return Ok(Cegis {
+                        program,
+                        initial: initial.to_vec(),
+                        counterexamples,
+                    })
+                }
+            }
+        }
+    }
+
+    // Every program consistent with all the examples, as of the last call to
+    // `synthesize`. `synthesize` stops intersecting once the best program fits
+    // every example, so this finishes the job first. Programs that produce a
+    // negative example are only taken out once they've been picked, so this
+    // can still have some.
+    pub fn version_space(&mut self) -> Option<Rc<DslVSA<D>>> {
+        if self.ex_vsas.len() < self.learned() {
+            return None;
+        }
+
+        let mut res = self.res?;
+        while self.intersected < self.ex_vsas.len() {
+            let next = self.arena.import(&self.ex_vsas[self.intersected]);
+            res = self.arena.intersect(res, next);
+            res = self.dsl.minimize(&mut self.arena, res);
+            self.intersected += 1;
+        }
+
+        self.res = Some(res);
+        Some(self.arena.export(res))
+    }
+
+    // Grows the bank one size at a time until `step` finds something in the
+    // example VSAs at that size
+    fn search<T>(
+        &mut self,
+        mut step: impl FnMut(&mut Self, &Meter) -> Option<T>,
+    ) -> Result<T, SynthError<DslAST<D>>> {
+        let (budget, cancel) = (self.budget.clone(), self.cancel.clone());
+        let meter = Meter::new(&budget, &cancel);
+
+        loop {
+            while self.ex_vsas.len() < self.learned() {
+                let vsa = self.learn_example(self.ex_vsas.len(), &meter);
+                if let Some(reason) = meter.exhausted() {
+                    return Err(self.timeout(reason));
+                }
+                self.ex_vsas.push(vsa);
+            }
+
+            if let Some(res) = step(self, &meter) {
+                return Ok(res);
+            }
+
+            if let Some(reason) = meter.exhausted() {
+                return Err(self.timeout(reason));
+            }
+
+            if self.size >= budget.max_size {
+                return Err(self.timeout(Exhausted::MaxSize));
+            }
+
+            self.size += 1;
+            self.ex_vsas.clear();
+            self.clear_intersection();
+
+            // the entries that did get added are still in `all_cache`, so
+            // growing to the same size next time picks up where this left off
+            if !self.grow(&meter) {
+                self.size -= 1;
+                return Err(self.timeout(meter.exhausted().unwrap()));
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.bank = Bank::new();
+        self.all_cache = HashMap::new();
+        self.size = 1;
+        self.ex_vsas.clear();
+        self.clear_intersection();
+        self.best = None;
+
+        for prim in self.dsl.seeds(&self.examples) {
+            self.bank.size_mut(1).push(AST::Lit(prim.clone()));
+            self.all_cache.insert(
+                vec![prim.clone(); self.columns()],
+                Rc::new(VSA::singleton(AST::Lit(prim))),
+            );
+        }
+    }
+
+    // examples first, then partial examples
+    fn columns(&self) -> usize {
+        self.examples.len() + self.partial.len()
+    }
+
+    // Partial examples only get a VSA (from `learn_partial`) when there
+    // aren't any exact ones. Otherwise they're only checked when picking a
+    // program, since they can't be learned as thoroughly and would cut good
+    // programs out of the intersection.
+    fn learned(&self) -> usize {
+        if self.examples.is_empty() {
+            self.partial.len()
+        } else {
+            self.examples.len()
+        }
+    }
+
+    fn inputs(&self) -> Vec<D::L> {
+        self.examples
+            .iter()
+            .map(|(inp, _)| inp.clone())
+            .chain(self.partial.iter().map(|(inp, _)| inp.clone()))
+            .collect()
+    }
+
+    fn add_column(&mut self, col: usize, inp: &D::L) {
+        self.all_cache = std::mem::take(&mut self.all_cache)
+            .into_iter()
+            .map(|(mut outs, vsa)| {
+                outs.insert(col, vsa.pick_one().unwrap().eval(inp));
+                (outs, vsa)
+            })
+            .collect();
+    }
+
+    fn remove_column(&mut self, col: usize) {
+        // entries that only differed on the removed example are now
+        // observationally equivalent, so only one of them is kept like
+        // `bottom_up` would have done
+        let mut all_cache: HashMap<Vec<D::L>, Rc<DslVSA<D>>> = HashMap::new();
+        for (mut outs, vsa) in std::mem::take(&mut self.all_cache) {
+            outs.remove(col);
+            all_cache.entry(outs).or_insert(vsa);
+        }
+        self.all_cache = all_cache;
+
+        if col < self.ex_vsas.len() {
+            self.ex_vsas.remove(col);
+        }
+        self.clear_intersection();
+    }
+
+    // whether `prog` fits example `col`, counting partial examples
+    fn fits(&self, prog: &DslAST<D>, col: usize) -> bool {
+        match self.examples.get(col) {
+            Some((inp, out)) => prog.eval(inp) == *out,
+            None => {
+                let (inp, partial) = &self.partial[col - self.examples.len()];
+                self.dsl.satisfies(partial, &prog.eval(inp))
+            }
+        }
+    }
+
+    fn grow(&mut self, meter: &Meter) -> bool {
+        bottom_up(
+            &self.dsl,
+            &self.inputs(),
+            self.size,
+            &mut self.all_cache,
+            &mut self.bank,
+            meter,
+        )
+    }
+
+    fn learn_example(&self, i: usize, meter: &Meter) -> Rc<DslVSA<D>> {
+        let mut cache: HashMap<D::L, Rc<DslVSA<D>>> = HashMap::new();
+        for (outs, vsa) in self.all_cache.iter() {
+            if let Some(v) = cache.get_mut(&outs[i]) {
+                *v = Rc::new(VSA::unify(vsa.clone(), v.clone()));
+            } else {
+                cache.insert(outs[i].clone(), vsa.clone());
+            }
+        }
+
+        match (self.examples.get(i), &self.sketch) {
+            (Some((inp, out)), Some(sketch)) => {
+                learn_sketch(&self.dsl, inp, out, sketch, &mut cache, &self.bank, meter)
+            }
+            (Some((inp, out)), None) => learn(&self.dsl, inp, out, &mut cache, &self.bank, meter),
+            (None, _) => {
+                let (inp, partial) = &self.partial[i - self.examples.len()];
+                learn_partial(&self.dsl, inp, partial, &mut cache, &self.bank, meter)
+            }
+        }
+    }
+
+    fn clear_intersection(&mut self) {
+        self.arena = Arena::new();
+        self.res = None;
+        self.intersected = 0;
+    }
+
+    fn intersect(&mut self, meter: &Meter) -> Option<DslAST<D>> {
+        let mut res = match self.res.take() {
+            Some(res) => res,
+            None => {
+                self.intersected = 1;
+                let first = self.arena.import(&self.ex_vsas[0]);
+                self.dsl.minimize(&mut self.arena, first)
+            }
+        };
+
+        // TODO:
+        // instead of pick_best, pick the best 10, and then
+        // check if it works on all examples
+        while self.intersected < self.ex_vsas.len() {
+            if let Some(prog) = Self::pick_allowed(&self.dsl, &self.negatives, &self.partial, self.sketch.as_ref(), &mut self.arena, &mut res, meter) {
+                if self.consider(&prog) == self.columns() {
+                    break;
+                };
+            }
+
+            if meter.is_exhausted() {
+                self.res = Some(res);
+                return None;
+            }
+
+            let nodes = self.arena.len();
+            let next = self.arena.import(&self.ex_vsas[self.intersected]);
+            res = self.arena.intersect(res, next);
+            res = self.dsl.minimize(&mut self.arena, res);
+            meter.add_vsa_nodes(self.arena.len() - nodes);
+            self.intersected += 1;
+        }
+
+        let best = Self::pick_allowed(&self.dsl, &self.negatives, &self.partial, self.sketch.as_ref(), &mut self.arena, &mut res, meter);
+        self.res = Some(res);
+        best
+    }
+
+    // The best program in `res` that doesn't produce any of the negative
+    // examples, satisfies every partial one and fills in the sketch (which
+    // `learn_partial` doesn't know about). Every program that doesn't is
+    // taken out of `res` by a difference, so it's only ever checked once.
+    // There can be exponentially many of them, so after `max_rejected` the
+    // budget counts as exhausted.
+    fn pick_allowed(
+        dsl: &D,
+        negatives: &[(D::L, D::L)],
+        partial: &[(D::L, D::Partial)],
+        sketch: Option<&DslAST<D>>,
+        arena: &mut Arena<D::L, D::F>,
+        res: &mut VsaId,
+        meter: &Meter,
+    ) -> Option<DslAST<D>> {
+        loop {
+            let prog = arena.pick_best(*res, |ast| dsl.rank(ast))?;
+            if !negatives.iter().any(|(inp, out)| prog.eval(inp) == *out)
+                && partial.iter().all(|(inp, p)| dsl.satisfies(p, &prog.eval(inp)))
+                && sketch.is_none_or(|sketch| prog.completes(sketch))
+            {
+                return Some(prog);
+            }
+
+            meter.add_rejected();
+            if meter.is_exhausted() {
+                return None;
+            }
+
+            let nodes = arena.len();
+            let forbidden = arena.leaf(std::iter::once(Rc::new(prog)));
+            let rest = arena.difference(*res, forbidden);
+            meter.add_vsa_nodes(arena.len() - nodes);
+
+            // `pick_best` would just pick it again
+            if rest == *res {
+                return None;
+            }
+            *res = rest;
+        }
+    }
+
+    // Intersects every subset of the example VSAs that leaves out at most
+    // `max_wrong` of them, fewest left out first. A program from a subset can
+    // still fit some of the examples left out, so they're only counted as
+    // wrong if it doesn't. Once leaving out more examples costs more than the
+    // best so far, nothing bigger can win. The winner comes with its score.
+    fn intersect_subsets(
+        &mut self,
+        max_wrong: usize,
+        wrong_cost: usize,
+        meter: &Meter,
+    ) -> Option<(usize, Noisy<DslAST<D>>)> {
+        // a separate arena, so the intersection of every example that
+        // `version_space` uses is left alone
+        let mut arena = Arena::new();
+        let ids = self
+            .ex_vsas
+            .iter()
+            .map(|vsa| arena.import(vsa))
+            .collect::<Vec<_>>();
+
+        let n = self.ex_vsas.len();
+        let max_wrong = if self.examples.is_empty() { 0 } else { max_wrong };
+        let mut best: Option<(usize, Noisy<DslAST<D>>)> = None;
+
+        for left_out in 0..=max_wrong.min(n - 1) {
+            if best.as_ref().is_some_and(|(score, _)| left_out * wrong_cost >= *score) {
+                break;
+            }
+
+            for kept in (0..n).combinations(n - left_out) {
+                if meter.is_exhausted() {
+                    return best;
+                }
+
+                let nodes = arena.len();
+                let mut res = kept[1..].iter().fold(ids[kept[0]], |res, i| {
+                    let res = arena.intersect(res, ids[*i]);
+                    self.dsl.minimize(&mut arena, res)
+                });
+                meter.add_vsa_nodes(arena.len() - nodes);
+
+                let Some(program) = Self::pick_allowed(&self.dsl, &self.negatives, &self.partial, self.sketch.as_ref(), &mut arena, &mut res, meter) else {
+                    continue;
+                };
+
+                self.consider(&program);
+                let wrong = (0..n).filter(|i| !self.fits(&program, *i)).collect::<Vec<_>>();
+                let score = self.dsl.rank(&program) + wrong_cost * wrong.len();
+
+                if best.as_ref().is_none_or(|(best, _)| score < *best) {
+                    best = Some((score, Noisy { program, wrong }));
+                }
+            }
+        }
+
+        best
+    }
+
+    // returns how many examples `prog` satisfies
+    fn consider(&mut self, prog: &DslAST<D>) -> usize {
+        let satisfied = (0..self.columns()).filter(|i| self.fits(prog, *i)).count();
+
+        if self.best.as_ref().is_none_or(|(n, _)| satisfied > *n) {
+            self.best = Some((satisfied, prog.clone()));
+        }
+
+        satisfied
+    }
+
+    fn timeout(&self, reason: Exhausted) -> SynthError<DslAST<D>> {
+        SynthError::Timeout {
+            reason,
+            best: self.best.as_ref().map(|(_, prog)| prog.clone()),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::str::FromStr;
+
+use crate::vsa::{Fun, Lit, Type};
+
+use super::grammar::parse_lit;
+
+type AST = crate::vsa::AST<Lit, Fun>;
+
+// Parses a program with holes written as function calls, e.g.
+//
+//     Concat(Slice(?, ?), '@company.com')
+//
+// Literals are written like in grammars (`X`, `$`, numbers, `true`/`false`),
+// except that strings can be in single or double quotes. The type of each `?`
+// is whatever its function takes there, and a `?` on its own is a string.
+pub fn parse_sketch(s: &str) -> Result<AST, String> {
+    let mut parser = Parser { s, pos: 0 };
+    let sketch = parser.expr(None)?;
+    parser.skip_whitespace();
+    if parser.pos < s.len() {
+        return Err(format!("unexpected {} after the sketch", &s[parser.pos..]));
+    }
+    Ok(sketch)
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.s[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.pos = self.s.len() - self.rest().trim_start().len();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expr(&mut self, expected: Option<Type>) -> Result<AST, String> {
+        self.skip_whitespace();
+
+        if self.eat('?') {
+            return Ok(AST::Hole(expected.unwrap_or(Type::String)));
+        }
+
+        let quote = self.rest().chars().next().filter(|c| *c == '\'' || *c == '"');
+        let (ast, ty) = match quote {
+            Some(quote) => {
+                let len = self.rest()[1..]
+                    .find(quote)
+                    .ok_or_else(|| format!("unterminated string {}", self.rest()))?;
+                let lit = Lit::StringConst(self.rest()[1..len + 1].to_string());
+                self.pos += len + 2;
+                (AST::Lit(lit), Type::String)
+            }
+            None => {
+                let len = self
+                    .rest()
+                    .find(|c: char| c == '(' || c == ')' || c == ',' || c.is_whitespace())
+                    .unwrap_or(self.rest().len());
+                let word = self.rest()[..len].to_string();
+                self.pos += len;
+
+                if self.eat('(') {
+                    let fun = Fun::from_str(&word)?;
+                    let args = self.args(&fun)?;
+                    (AST::App { fun, args }, fun.ret())
+                } else {
+                    let lit = parse_lit(&word)?;
+                    let ty = lit.ty();
+                    (AST::Lit(lit), ty)
+                }
+            }
+        };
+
+        match expected {
+            Some(expected) if expected != ty => Err(format!("{ast} is a {ty:?}, not a {expected:?}")),
+            _ => Ok(ast),
+        }
+    }
+
+    // everything after the opening paren
+    fn args(&mut self, fun: &Fun) -> Result<Vec<AST>, String> {
+        let types = fun.args();
+        let mut args = Vec::new();
+
+        if !self.eat(')') {
+            loop {
+                let ty = types.get(args.len()).copied();
+                args.push(self.expr(ty)?);
+                if self.eat(')') {
+                    break;
+                }
+                if !self.eat(',') {
+                    return Err(format!("expected , or ) at {}", self.rest()));
+                }
+            }
+        }
+
+        if args.len() != types.len() {
+            return Err(format!("{} takes {} arguments, got {}", fun.name(), types.len(), args.len()));
+        }
+        Ok(args)
+    }
+}
@@ -0,0 +1,77 @@
+use std::str::FromStr;
+
+use crate::vsa::Lit;
+
+use super::regex;
+
+// What an example says about its output, for when it isn't known exactly.
+// In text, e.g. from the web UI, these are written `prefix:ID-`,
+// `regex:\d{4}`, `length:1-5` and so on, with everything after the first
+// colon taken verbatim.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Spec {
+    Exact(String),
+    Prefix(String),
+    Suffix(String),
+    // the whole output has to match
+    Regex(String),
+    // inclusive, and in bytes like every location
+    Length(usize, usize),
+}
+
+impl Spec {
+    // The regex is checked here, since `matches` can't report a bad one
+    pub fn regex(re: &str) -> Result<Self, String> {
+        ::regex::Regex::new(&format!("^(?:{re})$")).map_err(|e| format!("bad regex {re}: {e}"))?;
+        Ok(Spec::Regex(re.to_string()))
+    }
+
+    pub fn length(min: usize, max: usize) -> Result<Self, String> {
+        if min > max {
+            return Err(format!("empty length range {min}-{max}"));
+        }
+        Ok(Spec::Length(min, max))
+    }
+
+    pub fn matches(&self, out: &Lit) -> bool {
+        let Lit::StringConst(out) = out else {
+            return false;
+        };
+
+        match self {
+            Spec::Exact(s) => out == s,
+            Spec::Prefix(s) => out.starts_with(s.as_str()),
+            Spec::Suffix(s) => out.ends_with(s.as_str()),
+            Spec::Regex(re) => regex(&format!("^(?:{re})$")).is_match(out),
+            Spec::Length(min, max) => (*min..=*max).contains(&out.len()),
+        }
+    }
+}
+
+impl FromStr for Spec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected kind:value, got {s}"))?;
+
+        let length = |n: &str| {
+            n.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("bad length {n}"))
+        };
+
+        match kind {
+            "exact" => Ok(Spec::Exact(rest.to_string())),
+            "prefix" => Ok(Spec::Prefix(rest.to_string())),
+            "suffix" => Ok(Spec::Suffix(rest.to_string())),
+            "regex" => Spec::regex(rest),
+            "length" => match rest.split_once('-') {
+                Some((min, max)) => Spec::length(length(min)?, length(max)?),
+                None => length(rest).map(|n| Spec::Length(n, n)),
+            },
+            _ => Err(format!("unknown kind of spec {kind}")),
+        }
+    }
+}
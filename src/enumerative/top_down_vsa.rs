@@ -1,17 +1,27 @@
 use std::{
+    cell::RefCell,
     collections::{HashMap, HashSet},
     num::NonZeroUsize,
     rc::Rc,
     sync::RwLock,
 };
 
-use itertools::iproduct;
+use itertools::{iproduct, Itertools};
 use lru::LruCache;
 use regex::Regex;
 
 use crate::{
     bank::Bank,
-    vsa::{Fun, Lit, Cost},
+    vsa::{
+        arena::{Arena, VsaId},
+        custom, Fun, Lit, Type,
+    },
+};
+
+use super::{
+    absint::{Abstract, CharSet},
+    engine::{Dsl, Goal, Witness},
+    Cegis, CegisError, CancelToken, DuetSession, Noisy, Spec, SynthConfig, SynthError,
 };
 
 use lazy_static::lazy_static;
@@ -19,6 +29,8 @@ use lazy_static::lazy_static;
 type VSA = crate::vsa::VSA<Lit, Fun>;
 type AST = crate::vsa::AST<Lit, Fun>;
 
+pub type SynthSession = DuetSession<StringDsl>;
+
 macro_rules! loc_pat {
     () => { AST::Lit(Lit::LocConst(_) | Lit::LocEnd) | AST::App { fun: Fun::Find | Fun::LocAdd | Fun::LocSub, .. } };
 }
@@ -43,373 +55,311 @@ pub fn regex(s: &String) -> Regex {
 // TODO:
 // add a substitute function
 
-pub fn top_down(examples: &[(Lit, Lit)]) -> Option<AST> {
-    let mut bank = Bank::new();
-    let mut regex_bank = Bank::new();
-    let mut all_cache = HashMap::new();
+pub fn top_down(examples: &[(Lit, Lit)], config: &SynthConfig) -> Option<AST> {
+    SynthSession::with_config(examples, config.clone())
+        .synthesize()
+        .ok()
+}
 
-    let mut char_sets = examples.iter().map(|(inp, out)| match (inp, out) {
-        (Lit::StringConst(inp), Lit::StringConst(out)) => inp
-            .chars()
-            .chain(out.chars())
-            .filter(|c| !c.is_alphanumeric())
-            .map(|c| match c {
-                '.' => Lit::StringConst("\\.".to_string()),
-                '{' => Lit::StringConst("\\{".to_string()),
-                '}' => Lit::StringConst("\\{".to_string()),
-                _ => Lit::StringConst(c.to_string()),
-            })
-            .collect::<HashSet<_>>(),
-        _ => HashSet::new(),
-    });
-    let intersection = char_sets
-        .next()
-        .map(|s1| {
-            s1.iter()
-                .filter(|c| char_sets.clone().all(|s2| s2.contains(c)))
-                .cloned()
-                .collect::<Vec<_>>()
-        })
-        .unwrap_or_default();
-
-    // dbg!(&intersection);
-
-    // TODO:
-    // a cache that is only applied to regexes
-    for prim in [
-        Lit::Input,
-        Lit::StringConst("".to_string()),
-        Lit::StringConst(" ".to_string()),
-        Lit::StringConst(".".to_string()),
-        Lit::LocConst(0),
-        Lit::LocConst(1),
-        Lit::LocEnd,
-    ]
-    .into_iter()
-    .chain(intersection.clone().into_iter())
-    {
-        bank.size_mut(1).push(AST::Lit(prim.clone()));
-        all_cache.insert(
-            std::iter::repeat(prim.clone())
-                .take(examples.len())
-                .collect(),
-            Rc::new(VSA::singleton(AST::Lit(prim.clone()))),
-        );
+pub fn top_down_with_budget(
+    examples: &[(Lit, Lit)],
+    config: &SynthConfig,
+    cancel: &CancelToken,
+) -> Result<AST, SynthError> {
+    let mut session = SynthSession::with_config(examples, config.clone());
+    session.set_cancel_token(cancel.clone());
+    session.synthesize()
+}
+
+// `negatives` are (input, output) pairs the program must not produce
+pub fn top_down_with_negatives(
+    examples: &[(Lit, Lit)],
+    negatives: &[(Lit, Lit)],
+    config: &SynthConfig,
+) -> Option<AST> {
+    let mut session = SynthSession::with_config(examples, config.clone());
+    for (inp, out) in negatives {
+        session.add_negative(inp.clone(), out.clone());
     }
+    session.synthesize().ok()
+}
+
+// The best program that fills in the holes of `sketch`, see `parse_sketch`
+pub fn complete_sketch(sketch: &AST, examples: &[(Lit, Lit)], config: &SynthConfig) -> Option<AST> {
+    let mut session = SynthSession::with_config(examples, config.clone());
+    session.set_sketch(Some(sketch.clone()));
+    session.synthesize().ok()
+}
 
-    for prim in [
-        Lit::StringConst("\\d".to_string()),
-        Lit::StringConst("\\b".to_string()),
-        Lit::StringConst("[a-z]".to_string()),
-        Lit::StringConst("[A-Z]".to_string()),
-    ]
-    .into_iter()
-    .chain(intersection.into_iter())
-    {
-        regex_bank.size_mut(1).push(AST::Lit(prim.clone()));
+// Examples whose outputs can be partial, see `Spec`
+pub fn top_down_with_specs(examples: &[(Lit, Spec)], config: &SynthConfig) -> Option<AST> {
+    let mut session = SynthSession::with_config(&[], config.clone());
+    for (inp, spec) in examples {
+        session.add_spec(inp.clone(), spec.clone());
     }
+    session.synthesize().ok()
+}
 
-    let enable_bools = examples.iter().any(|(_, out)| matches!(out, Lit::BoolConst(_)));
-
-    let mut size = 1;
-    let inps = examples.iter().map(|(inp, _)| inp);
-
-    while size <= 6 {
-        bottom_up(
-            inps.clone(),
-            size,
-            &mut all_cache,
-            &mut bank,
-            &mut regex_bank,
-            enable_bools
-        );
-        // dbg!(bank.total_entries());
-        let mut ex_vsas = examples.iter().enumerate().map(|(i, (inp, out))| {
-            let mut cache: HashMap<Lit, Rc<VSA>> = HashMap::new();
-            for (outs, vsa) in all_cache.iter() {
-                if let Some(v) = cache.get_mut(&outs[i]) {
-                    *v = Rc::new(VSA::unify(vsa.clone(), v.clone()));
-                } else {
-                    cache.insert(outs[i].clone(), vsa.clone());
-                }
-            }
+// Allows up to `config.max_wrong` wrong examples, see
+// `DuetSession::synthesize_noisy`
+pub fn top_down_noisy(
+    examples: &[(Lit, Lit)],
+    config: &SynthConfig,
+    cancel: &CancelToken,
+) -> Result<Noisy<AST>, SynthError> {
+    let mut session = SynthSession::with_config(examples, config.clone());
+    session.set_cancel_token(cancel.clone());
+    session.synthesize_noisy(config.max_wrong, config.wrong_cost)
+}
 
-            learn(inp, out, &mut cache, &bank)
-        });
+// Synthesizes from a few representative rows and adds the rows the program
+// gets wrong one at a time, see `DuetSession::synthesize_cegis`
+pub fn top_down_cegis(
+    rows: &[(Lit, Lit)],
+    config: &SynthConfig,
+    cancel: &CancelToken,
+) -> Result<Cegis<AST>, CegisError<AST>> {
+    let mut session = SynthSession::with_config(&[], config.clone());
+    session.set_cancel_token(cancel.clone());
+    session.synthesize_cegis(rows, &representatives(rows, config.cegis_initial))
+}
 
-        let mut res = ex_vsas.next().unwrap();
+// The first row of each shape, up to `n` of them. The shape of a string is
+// what's left after replacing every run of letters with `a` and every run of
+// digits with `0`, so "John Smith, 42" is "a a, 0".
+fn representatives(rows: &[(Lit, Lit)], n: usize) -> Vec<usize> {
+    let shape = |lit: &Lit| match lit {
+        Lit::StringConst(s) => s
+            .chars()
+            .map(|c| match c {
+                _ if c.is_alphabetic() => 'a',
+                _ if c.is_numeric() => '0',
+                _ => c,
+            })
+            .dedup_by(|a, b| a == b && (*a == 'a' || *a == '0'))
+            .collect::<String>(),
+        lit => format!("{lit:?}"),
+    };
 
-        // TODO:
-        // instead of pick_best, pick the best 10, and then
-        // check if it works on all examples
-        for vsa in ex_vsas {
-            if let Some(prog) = res.pick_best(|ast| ast.cost()) {
-                if examples.iter().all(|(inp, out)| prog.eval(inp) == *out) {
-                    break;
-                };
-            }
+    rows.iter()
+        .enumerate()
+        .unique_by(|(_, (inp, out))| (shape(inp), shape(out)))
+        .map(|(i, _)| i)
+        .take(n.max(1))
+        .collect()
+}
 
-            res = Rc::new(res.intersect(vsa.as_ref()));
-        }
+impl SynthSession {
+    pub fn new(examples: &[(Lit, Lit)]) -> Self {
+        SynthSession::with_config(examples, SynthConfig::default())
+    }
 
-        match res.pick_best(|ast| ast.cost()) {
-            res @ Some(_) => return res,
-            None => size += 1,
+    pub fn with_config(examples: &[(Lit, Lit)], config: SynthConfig) -> Self {
+        let budget = config.budget.clone();
+        let mut session = DuetSession::from_dsl(StringDsl::new(config), examples);
+        session.set_budget(budget);
+        session
+    }
+
+    // exact specs are learned top down like any other example
+    pub fn add_spec(&mut self, inp: Lit, spec: Spec) {
+        match spec {
+            Spec::Exact(out) => self.add_example(inp, Lit::StringConst(out)),
+            spec => self.add_partial(inp, spec),
         }
     }
+}
 
-    None
+// The string transformation language from `vsa.rs`
+pub struct StringDsl {
+    config: SynthConfig,
+    shared_chars: HashSet<Lit>,
+    enable_bools: bool,
+    regex_bank: Bank<AST>,
+    // the characters anything in the bank outputs on an input, and how big
+    // the bank was when they were worked out
+    bank_chars: RefCell<HashMap<Lit, (usize, CharSet)>>,
 }
 
-// TODO:
-// there's still an issue with cycles here
-// maybe still needs a queue
-fn learn(inp: &Lit, out: &Lit, cache: &mut HashMap<Lit, Rc<VSA>>, bank: &Bank<AST>) -> Rc<VSA> {
-    let mut unifier = Vec::new();
-    if let Some(res) = cache.get(out) {
-        unifier.push(res.as_ref().clone());
-        // return res.clone();
+impl StringDsl {
+    pub fn new(config: SynthConfig) -> Self {
+        StringDsl {
+            config,
+            shared_chars: HashSet::new(),
+            enable_bools: false,
+            regex_bank: Bank::new(),
+            bank_chars: RefCell::new(HashMap::new()),
+        }
     }
 
-    macro_rules! universal_witness {
-        ($p:pat) => {
-            bank.entries.iter().flat_map(|entry| entry.iter().filter(|ast| matches!(ast, $p)))
+    pub fn config(&self) -> &SynthConfig {
+        &self.config
+    }
+
+    // Every character a program that isn't just a constant could have in its
+    // output on `inp`, short of a function with a witness we can't see into
+    fn reachable_chars(&self, inp: &Lit, bank: &Bank<AST>) -> CharSet {
+        if self.funs().iter().any(|fun| matches!(fun, Fun::Custom(_))) {
+            return CharSet::ALL;
+        }
+
+        let mut cache = self.bank_chars.borrow_mut();
+        match cache.get(inp) {
+            Some((entries, chars)) if *entries == bank.total_entries() => *chars,
+            _ => {
+                let chars = bank
+                    .entries
+                    .iter()
+                    .flatten()
+                    .filter_map(|ast| match ast.eval(inp) {
+                        Lit::StringConst(s) => Some(CharSet::of(&s)),
+                        _ => None,
+                    })
+                    .fold(CharSet::default(), CharSet::union);
+                let chars = match inp {
+                    Lit::StringConst(inp) => chars.union(CharSet::of(inp)),
+                    _ => chars,
+                };
+                cache.insert(inp.clone(), (bank.total_entries(), chars));
+                chars
+            }
         }
     }
 
-    macro_rules! multi_match {
-        ($v:expr, $($p:pat $(if $guard:expr)? => $res:expr),*) => {
-            $(
-                match $v {
-                    $p $(if $guard)? => $res,
-                    _ => {},
-                }
-            )*
-        };
+    // most likely first if the config has weights, which means generating
+    // everything up front
+    fn by_likelihood<'a>(&self, candidates: impl Iterator<Item = AST> + 'a) -> Box<dyn Iterator<Item = AST> + 'a> {
+        match &self.config.weights {
+            Some(weights) => Box::new(
+                candidates
+                    .map(|ast| (weights.bits(&ast), ast))
+                    .sorted_by(|(a, _), (b, _)| a.total_cmp(b))
+                    .map(|(_, ast)| ast),
+            ),
+            None => Box::new(candidates),
+        }
     }
+}
 
-    multi_match!((out, inp),
-    // (Lit::StringConst(s), _) if s.as_str() == " " => {
-    //     unifier.push(VSA::singleton(AST::Lit(Lit::StringConst(" ".to_string()))))
-    // },
-    // (Lit::StringConst(s), _) if s.as_str() == "." => {
-    //     unifier.push(VSA::singleton(AST::Lit(Lit::StringConst(".".to_string()))))
-    // },
-    //
-    // TODO:
-    // this makes it impossible to learn in one shot
-    (Lit::StringConst(s), _) => {
-        unifier.push(VSA::singleton(AST::Lit(Lit::StringConst(s.clone()))))
-    },
+impl Dsl for StringDsl {
+    type L = Lit;
+    type F = Fun;
+    type Partial = Spec;
 
-    (Lit::BoolConst(b), _) => {
-        unifier.push(VSA::singleton(AST::Lit(Lit::BoolConst(*b))))
-    },
+    // the shared characters only ever shrink when an example is added, unless
+    // it's the first one
+    fn seeds_changed(&self, examples: &[(Lit, Lit)]) -> bool {
+        !shared_chars(examples).is_subset(&self.shared_chars)
+            || enable_bools(examples) != self.enable_bools
+    }
 
-    (Lit::LocConst(n), _) => {
-        unifier.push(VSA::singleton(AST::Lit(Lit::LocConst(*n))))
-    },
+    fn seeds(&mut self, examples: &[(Lit, Lit)]) -> Vec<Lit> {
+        self.shared_chars = shared_chars(examples);
+        self.enable_bools = enable_bools(examples);
+        self.bank_chars.borrow_mut().clear();
 
-    (Lit::LocConst(n), Lit::StringConst(inp_str)) if inp_str.len() == *n => {
-        unifier.push(VSA::singleton(AST::Lit(Lit::LocEnd)));
-    },
+        // TODO:
+        // a cache that is only applied to regexes
+        self.regex_bank = Bank::new();
+        for prim in self
+            .config
+            .seed_regexes()
+            .chain(self.shared_chars.iter().cloned())
+        {
+            self.regex_bank.size_mut(1).push(AST::Lit(prim));
+        }
 
-    (Lit::BoolConst(b), _) => {
-        let s = iproduct!(universal_witness!(loc_pat!()), universal_witness!(loc_pat!())).map(|(lhs, rhs)| {
-            AST::App {
-                fun: Fun::Equal,
-                args: vec![lhs.clone(), rhs.clone()],
+        self.config
+            .seed_constants()
+            .chain(self.shared_chars.iter().cloned())
+            .collect()
+    }
+
+    // registered functions are only used once the config enables them
+    fn funs(&self) -> Vec<Fun> {
+        Fun::all()
+            .into_iter()
+            .filter(|fun| self.config.is_enabled(*fun))
+            .collect()
+    }
+
+    fn lit_witness(&self, inp: &Lit, out: &Lit) -> Vec<AST> {
+        match (out, inp) {
+            // TODO:
+            // this makes it impossible to learn in one shot
+            (Lit::StringConst(_) | Lit::BoolConst(_), _) => vec![AST::Lit(out.clone())],
+            (Lit::LocConst(n), Lit::StringConst(inp_str)) if inp_str.len() == *n => {
+                vec![AST::Lit(out.clone()), AST::Lit(Lit::LocEnd)]
             }
-        }).map(Rc::new).collect();
-        unifier.push(VSA::Leaf(s));
-    },
+            (Lit::LocConst(_), _) => vec![AST::Lit(out.clone())],
+            _ => vec![],
+        }
+    }
 
-    (Lit::StringConst(s), Lit::StringConst(inp_str)) if s.contains(inp_str) => {
-        let re = regex(inp_str);
+    fn enumerate<'a>(&'a self, size: usize, bank: &'a Bank<AST>) -> Box<dyn Iterator<Item = AST> + 'a> {
+        let (config, regex_bank) = (&self.config, &self.regex_bank);
 
-        re.find_iter(s)
-            .map(|m| {
-                let start = m.start();
-                let end = m.end();
-                let start_vsa = learn(inp, &Lit::StringConst(s[0..start].to_string()), cache, bank);
-                let end_vsa = learn(inp, &Lit::StringConst(s[end..].to_string()), cache, bank);
-                // dbg!(start, end, s[0..start].to_string(), s[end..].to_string(), start_vsa.clone(), end_vsa.clone());
-                // TODO: maybe add a simplify function to the AST
-                VSA::Join {
-                    op: Fun::Concat,
-                    children: vec![
-                        start_vsa,
-                        Rc::new(VSA::Join {
-                            op: Fun::Concat,
-                            children: vec![
-                                learn(inp, &Lit::Input, cache, bank),
-                                end_vsa,
-                            ],
-                        }),
-                    ],
-                }
-            })
-        .for_each(|vsa| unifier.push(vsa));
-        },
-
-        (Lit::StringConst(s), Lit::StringConst(inp_str)) if inp_str.contains(s) => {
-            let re = regex(s);
-
-            re.find_iter(inp_str)
-                .map(|m| {
-                    let start = m.start();
-                    let end = m.end();
-                    let start_vsa = learn(inp, &Lit::LocConst(start), cache, bank);
-                    let end_vsa = learn(inp, &Lit::LocConst(end), cache, bank);
-                    VSA::Join {
-                        op: Fun::Slice,
-                        children: vec![
-                            start_vsa,
-                            end_vsa,
-                        ],
-                    }
-                })
-            .for_each(|vsa| unifier.push(vsa));
-            // let start = inp_str.find(s).unwrap();
-            // let end = start + s.len();
-            // // dbg!(s, start, end);
-            // let start_vsa = learn(inp, &Lit::LocConst(start), cache);
-            // let end_vsa = learn(inp, &Lit::LocConst(end), cache);
-            // unifier.push(VSA::Join {
-            //     op: Fun::Slice,
-            //     children: vec![
-            //         start_vsa,
-            //         end_vsa,
-            //     ],
-            // });
-            },
-
-            (Lit::StringConst(s), Lit::StringConst(inp_str)) if !inp_str.contains(s) && !s.contains(inp_str) => {
-                let set = (1..s.len())
-                    .map(|i| VSA::Join {
-                        op: Fun::Concat,
-                        children: vec![
-                            learn(
-                                inp,
-                                &Lit::StringConst(s[0..i].to_string()),
-                                cache,
-                                bank,
-                            ),
-                            learn(
-                                inp,
-                                &Lit::StringConst(s[i..].to_string()),
-                                cache,
-                                bank,
-                            ),
-                        ],
-                    })
-                .map(Rc::new)
-                .collect();
+        if let Some(grammar) = &config.grammar {
+            return self.by_likelihood(grammar.enumerate(size, bank, regex_bank, self.enable_bools));
+        }
 
-                unifier.push(VSA::Union(set));
-            }
+        // TODO: probably remove LocAdd and LocSub in favor for LocInc and LocDec or something
+        use crate::vsa::{Fun::*, Lit::*};
 
-    // TODO: figure out the index
-    // (Lit::LocConst(n), Lit::StringConst(s)) if s.chars().nth(*n).is_some_and(|ch| ch == ' ') => {
-    //     let lhs = Rc::new(VSA::singleton(AST::Lit(Lit::Input)));
-    //     let space = cache.get(&Lit::StringConst(" ".to_string())).unwrap().clone();
-    //     let wb = cache.get(&Lit::StringConst("\\b".to_string())).unwrap().clone();
-
-    //     unifier.push(VSA::Join {
-    //         op: Fun::Find,
-    //         children: vec![lhs.clone(), space],
-    //     });
-
-    //     if s.chars().nth(n - 1).is_some_and(|ch| ch.is_alphanumeric()) {
-    //         unifier.push(VSA::Join {
-    //             op: Fun::Find,
-    //             children: vec![lhs, wb],
-    //         });
-    //     }
-    // }
-    );
-
-    let res = unifier
-        .into_iter()
-        .map(Rc::new)
-        .fold(Rc::new(VSA::empty()), |acc, x| Rc::new(VSA::unify(acc, x)));
-
-    match res.as_ref() {
-        VSA::Union(s) if s.is_empty() => todo!(), //bottom up?
-        _ => {}
-    }
+        // the regex bank only ever has the seeds
+        #[rustfmt::skip]
+        let regexes_of_size = move |n: usize| {
+            regex_bank.entries.get(n - 1).into_iter().flatten()
+        };
 
-    // cache.insert(out.clone(), res.clone());
-    res
-}
+        #[rustfmt::skip]
+        let strings_of_size = move |n: usize| {
+            bank.size(n).iter().filter(|e| {
+                matches!(
+                    e,
+                    AST::Lit(Input | StringConst(_)) | AST::App { fun: Concat | Slice, .. }
+                ) || matches!(e, AST::App { fun: fun @ Custom(_), .. } if fun.ret() == Type::String)
+            })
+        };
 
-fn bottom_up<'a>(
-    inps: impl Iterator<Item = &'a Lit> + Clone,
-    size: usize,
-    cache: &mut HashMap<Vec<Lit>, Rc<VSA>>,
-    bank: &mut Bank<AST>,
-    regex_bank: &mut Bank<AST>,
-    enable_bools: bool,
-) {
-    // dbg!(size);
-    bank.grow_to(size);
-    regex_bank.grow_to(size);
-    // builds a VSA for a given I/O example
-    // then we can add these to the cache for `learn`
-
-    // TODO: a better way to keep track of size, make the bank store
-    // by size so that we can just directly make expressions of the correct size
-    //
-    // TODO: probably remove LocAdd and LocSub in favor for LocInc and LocDec or something
-    use crate::vsa::{Fun::*, Lit::*};
-
-    #[rustfmt::skip]
-    let regexes_of_size = |n: usize| {
-        regex_bank.size(n).iter()
-    };
+        #[rustfmt::skip]
+        let locs_of_size = move |n: usize| {
+            bank.size(n).iter().filter(|e| {
+                matches!(
+                    e,
+                    AST::Lit(LocConst(_) | LocEnd) | AST::App { fun: Find | LocAdd | LocSub, .. }
+                ) || matches!(e, AST::App { fun: fun @ Custom(_), .. } if fun.ret() == Type::Loc)
+            })
+        };
 
-    #[rustfmt::skip]
-    let strings_of_size = |n: usize| {
-        bank.size(n).iter().filter(|e| {
-            matches!(
-                e,
-                AST::Lit(Input | StringConst(_)) | AST::App { fun: Concat | Slice, .. }
-            )
-        })
-    };
+        let bools_of_size = move |n: usize| {
+            bank.size(n).iter().filter(|e| {
+                matches!(e, AST::Lit(BoolConst(_)) | AST::App { fun: Equal, .. })
+                    || matches!(e, AST::App { fun: fun @ Custom(_), .. } if fun.ret() == Type::Bool)
+            })
+        };
 
-    #[rustfmt::skip]
-    let locs_of_size = |n: usize| {
-        bank.size(n).iter().filter(|e| {
-            matches!(
-                e,
-                AST::Lit(LocConst(_) | LocEnd) | AST::App { fun: Find | LocAdd | LocSub, .. }
-            )
-        })
-    };
+        let of_type = move |ty: Type, n: usize| -> Box<dyn Iterator<Item = &'a AST> + 'a> {
+            match ty {
+                Type::String => Box::new(strings_of_size(n)),
+                Type::Loc => Box::new(locs_of_size(n)),
+                Type::Bool => Box::new(bools_of_size(n)),
+            }
+        };
 
-    #[rustfmt::skip]
-    let bools_of_size = |n: usize| {
-        bank.size(n).iter().filter(|e| {
-            matches!(
-                e,
-                AST::Lit(BoolConst(_)) | AST::App { fun: Equal, .. }
-            )
-        })
-    };
+        // a size of 0 disables the whole family below
+        let size_for = |fun: Fun| if config.enumerates(fun) { size } else { 0 };
 
-    let adjs: Vec<AST> = {
-        let loc_adds = (1..size).flat_map(|i| {
+        let loc_adds = (1..size_for(Fun::LocAdd)).flat_map(move |i| {
             let lhs_size = i;
             let rhs_size = size - i;
-            // dbg!(locs_of_size(dbg!(lhs_size)).collect::<Vec<_>>());
             iproduct!(locs_of_size(lhs_size), locs_of_size(rhs_size)).map(|(lhs, rhs)| AST::App {
                 fun: Fun::LocAdd,
                 args: vec![lhs.clone(), rhs.clone()],
             })
         });
 
-        let loc_subs = (1..size).flat_map(|i| {
+        let loc_subs = (1..size_for(Fun::LocSub)).flat_map(move |i| {
             let lhs_size = i;
             let rhs_size = size - i;
             iproduct!(locs_of_size(lhs_size), locs_of_size(rhs_size)).map(|(lhs, rhs)| AST::App {
@@ -420,7 +370,8 @@ fn bottom_up<'a>(
 
         // I guess the concat witness function is complete
         // so this isn't needed
-        let concats = (1..size).flat_map(|i| {
+        let concat_size = if config.enumerate_concats { size_for(Fun::Concat) } else { 0 };
+        let concats = (1..concat_size).flat_map(move |i| {
             let lhs_size = i;
             let rhs_size = size - i;
             iproduct!(strings_of_size(lhs_size), strings_of_size(rhs_size)).map(|(lhs, rhs)| {
@@ -431,7 +382,7 @@ fn bottom_up<'a>(
             })
         });
 
-        let re_concats = (1..size).flat_map(|i| {
+        let re_concats = (1..size_for(Fun::Concat)).flat_map(move |i| {
             let lhs_size = i;
             let rhs_size = size - i;
             iproduct!(regexes_of_size(lhs_size), regexes_of_size(rhs_size)).map(|(lhs, rhs)| {
@@ -442,12 +393,12 @@ fn bottom_up<'a>(
             })
         });
 
-        let finds = (1..size - 1).flat_map(|l| {
+        let find_size = size_for(Fun::Find).max(size_for(Fun::FindEnd));
+        let finds = (1..find_size.saturating_sub(1)).flat_map(move |l| {
             (l + 1..size).flat_map(move |r| {
                 let lhs_size = l;
                 let rhs_size = r - l;
                 let index_size = size - r;
-                // dbg!(lhs_size, rhs_size, index_size);
                 iproduct!(
                     strings_of_size(lhs_size),
                     // strings_of_size(rhs_size),
@@ -466,10 +417,11 @@ fn bottom_up<'a>(
                         },
                     ]
                 })
+                .filter(|find| matches!(find, AST::App { fun, .. } if config.enumerates(*fun)))
             })
         });
 
-        let slices = (1..size).flat_map(|i| {
+        let slices = (1..size_for(Fun::Slice)).flat_map(move |i| {
             let lhs_size = i;
             let rhs_size = size - i;
             iproduct!(locs_of_size(lhs_size), locs_of_size(rhs_size)).map(|(lhs, rhs)| AST::App {
@@ -478,16 +430,15 @@ fn bottom_up<'a>(
             })
         });
 
-        let re_groups = (1..size - 1).flat_map(|size| {
+        let re_groups = (1..size_for(Fun::Concat).saturating_sub(1)).flat_map(move |size| {
             strings_of_size(size).map(|e| AST::App {
                 fun: Fun::Concat,
                 args: vec![e.clone(), AST::Lit(Lit::StringConst("+".to_string()))],
             })
         });
-        // dbg!(re_groups.clone().collect::<Vec<_>>());
 
-        let loc_eq_size = if enable_bools { size } else { 0 };
-        let loc_eqs = (1..loc_eq_size).flat_map(|i| {
+        let loc_eq_size = if self.enable_bools { size_for(Fun::Equal) } else { 0 };
+        let loc_eqs = (1..loc_eq_size).flat_map(move |i| {
             let lhs_size = i;
             let rhs_size = size - i;
             iproduct!(locs_of_size(lhs_size), locs_of_size(rhs_size)).map(|(lhs, rhs)| AST::App {
@@ -496,34 +447,273 @@ fn bottom_up<'a>(
             })
         });
 
-        // loc_adds
-        //     .chain(loc_subs)
-        re_concats
-            // .chain(concats)
-            .chain(slices)
-            .chain(finds)
-            .chain(re_groups)
-            .chain(loc_eqs)
+        // every way of splitting the arguments' sizes, then every choice of
+        // arguments with those sizes. Size 1 is only the seeds, so calls
+        // without arguments come with size 2.
+        let customs = self
+            .funs()
+            .into_iter()
+            .filter(|fun| matches!(fun, Custom(_)) && config.enumerates(*fun))
+            .flat_map(move |fun| {
+                let Custom(id) = fun else { unreachable!() };
+                let arg_types = custom::args(id);
+                let sizes = match arg_types.len() {
+                    0 if size == 2 => vec![vec![]],
+                    n => compositions(size - 1, n),
+                };
+                sizes
+                    .into_iter()
+                    .flat_map(move |sizes| {
+                        let options = arg_types
+                            .iter()
+                            .zip(sizes)
+                            .map(|(ty, n)| of_type(*ty, n).cloned().collect::<Vec<_>>())
+                            .collect();
+                        arg_tuples(options).map(move |args| AST::App { fun, args })
+                    })
+            });
+
+        self.by_likelihood(
+            loc_adds
+                .chain(loc_subs)
+                .chain(re_concats)
+                .chain(concats)
+                .chain(slices)
+                .chain(finds)
+                .chain(re_groups)
+                .chain(loc_eqs)
+                .chain(customs),
+        )
     }
-    .filter(|adj| {
-        let outs = inps.clone().map(|inp| adj.eval(inp)).collect::<Vec<_>>();
-        use std::collections::hash_map::Entry;
-
-        // dbg!(adj.size(), size);
-        // dbg!(adj.size(), size, bank.len());
-        if let Entry::Vacant(e) = cache.entry(outs) {
-            e.insert(Rc::new(VSA::singleton(adj.clone())));
-            true
+
+    fn rank(&self, ast: &AST) -> usize {
+        self.config.rank(ast)
+    }
+
+    // every level of the bank adds a node, and a node costs at least 1
+    fn min_rank(&self, size: usize) -> usize {
+        if self.config.ranks_by_cost() {
+            size
         } else {
-            false
+            0
+        }
+    }
+
+    fn minimize(&self, arena: &mut Arena<Lit, Fun>, id: VsaId) -> VsaId {
+        match self.config.max_cost {
+            Some(max_cost) => arena.prune(id, max_cost),
+            None => id,
+        }
+    }
+
+    fn satisfies(&self, spec: &Spec, out: &Lit) -> bool {
+        spec.matches(out)
+    }
+
+    fn may_output(&self, inp: &Lit, sketch: &AST, out: &Lit) -> bool {
+        match (Abstract::eval(sketch, inp), out) {
+            (Some(abs), Lit::StringConst(out)) => abs.admits(out),
+            _ => true,
+        }
+    }
+
+    // A known prefix or suffix can be concatenated with any string from the
+    // bank. Exact specs should go through `SynthSession::add_spec` instead,
+    // which learns them as normal examples.
+    fn partial_witness(&self, inp: &Lit, spec: &Spec, bank: &Bank<AST>) -> Vec<(Fun, Vec<Goal<StringDsl>>)> {
+        let strings = || {
+            Goal::Fixed(Rc::new(VSA::Leaf(
+                bank.entries
+                    .iter()
+                    .flatten()
+                    .filter(|ast| matches!(ast.eval(inp), Lit::StringConst(_)))
+                    .cloned()
+                    .map(Rc::new)
+                    .collect(),
+            )))
+        };
+
+        match spec {
+            Spec::Prefix(s) => vec![(Fun::Concat, vec![Goal::Learn(Lit::StringConst(s.clone())), strings()])],
+            Spec::Suffix(s) => vec![(Fun::Concat, vec![strings(), Goal::Learn(Lit::StringConst(s.clone()))])],
+            _ => vec![],
+        }
+    }
+}
+
+impl Witness<StringDsl> for Fun {
+    fn witness(&self, inp: &Lit, out: &Lit, dsl: &StringDsl, bank: &Bank<AST>) -> Vec<Vec<Goal<StringDsl>>> {
+        match (self, out, inp) {
+            (Fun::Equal, Lit::BoolConst(_), _) => {
+                let locs = Rc::new(VSA::Leaf(
+                    bank.entries
+                        .iter()
+                        .flatten()
+                        .filter(|ast| matches!(ast, loc_pat!()))
+                        .cloned()
+                        .map(Rc::new)
+                        .collect(),
+                ));
+                vec![vec![Goal::Fixed(locs.clone()), Goal::Fixed(locs)]]
+            }
+
+            (Fun::Concat, Lit::StringConst(s), Lit::StringConst(inp_str)) if s.contains(inp_str) => {
+                let re = regex(inp_str);
+
+                // TODO: maybe add a simplify function to the AST
+                re.find_iter(s)
+                    .map(|m| {
+                        vec![
+                            Goal::Learn(Lit::StringConst(s[0..m.start()].to_string())),
+                            Goal::Join(
+                                Fun::Concat,
+                                vec![
+                                    Goal::Learn(Lit::Input),
+                                    Goal::Learn(Lit::StringConst(s[m.end()..].to_string())),
+                                ],
+                            ),
+                        ]
+                    })
+                    .collect()
+            }
+
+            // A piece without any character from the input or the bank can
+            // only be built out of constants. Splitting between two of those
+            // only gives programs that cost more than the whole constant,
+            // which is always in the VSA anyway. Other rankings can put
+            // constants anywhere, so they get every split.
+            (Fun::Concat, Lit::StringConst(s), Lit::StringConst(inp_str)) if !inp_str.contains(s) => {
+                let prune = dsl.config.ranks_by_cost();
+                let reachable = dsl.reachable_chars(inp, bank);
+                let constant_only = |piece: &str| !CharSet::of(piece).intersects(reachable);
+
+                (1..s.len())
+                    .filter(|i| !(prune && constant_only(&s[..*i]) && constant_only(&s[*i..])))
+                    .map(|i| {
+                        vec![
+                            Goal::Learn(Lit::StringConst(s[0..i].to_string())),
+                            Goal::Learn(Lit::StringConst(s[i..].to_string())),
+                        ]
+                    })
+                    .collect()
+            }
+
+            (Fun::Slice, Lit::StringConst(s), Lit::StringConst(inp_str)) if inp_str.contains(s) => {
+                let re = regex(s);
+
+                re.find_iter(inp_str)
+                    .map(|m| vec![Goal::Learn(Lit::LocConst(m.start())), Goal::Learn(Lit::LocConst(m.end()))])
+                    .collect()
+            }
+
+            // an argument that has to be the output itself would only send
+            // `learn` around in a circle
+            (Fun::Custom(id), _, _) if out != &Lit::Input && out.ty() == self.ret() => custom::witness(*id, inp, out)
+                .into_iter()
+                .filter(|args| !args.contains(out))
+                .map(|args| args.into_iter().map(Goal::Learn).collect())
+                .collect(),
+
+            // Every seed regex that has an occurrence starting (or ending) at
+            // `n`, grouped by which occurrence it is
+            (Fun::Find | Fun::FindEnd, Lit::LocConst(n), Lit::StringConst(inp_str)) => {
+                let input = Rc::new(VSA::singleton(AST::Lit(Lit::Input)));
+
+                dsl.regex_bank
+                    .entries
+                    .iter()
+                    .flatten()
+                    .filter_map(|ast| match ast {
+                        AST::Lit(Lit::StringConst(re)) => regex(re)
+                            .find_iter(inp_str)
+                            .position(|m| match self {
+                                Fun::Find => m.start() == *n,
+                                _ => m.end() == *n,
+                            })
+                            .map(|k| (k, ast.clone())),
+                        _ => None,
+                    })
+                    .into_group_map()
+                    .into_iter()
+                    .sorted_by_key(|(k, _)| *k)
+                    .map(|(k, res)| {
+                        let res = VSA::Leaf(res.into_iter().map(Rc::new).collect());
+                        let k = VSA::singleton(AST::Lit(Lit::LocConst(k)));
+                        vec![
+                            Goal::Fixed(input.clone()),
+                            Goal::Fixed(Rc::new(res)),
+                            Goal::Fixed(Rc::new(k)),
+                        ]
+                    })
+                    .collect()
+            }
+
+            _ => vec![],
         }
-    })
-    .collect::<Vec<_>>();
+    }
+}
+
+pub(super) fn shared_chars(examples: &[(Lit, Lit)]) -> HashSet<Lit> {
+    let mut char_sets = examples.iter().map(|(inp, out)| match (inp, out) {
+        (Lit::StringConst(inp), Lit::StringConst(out)) => inp
+            .chars()
+            .chain(out.chars())
+            .filter(|c| !c.is_alphanumeric())
+            .map(|c| match c {
+                '.' => Lit::StringConst("\\.".to_string()),
+                '{' => Lit::StringConst("\\{".to_string()),
+                '}' => Lit::StringConst("\\{".to_string()),
+                _ => Lit::StringConst(c.to_string()),
+            })
+            .collect::<HashSet<_>>(),
+        _ => HashSet::new(),
+    });
+
+    char_sets
+        .next()
+        .map(|s1| {
+            s1.iter()
+                .filter(|c| char_sets.clone().all(|s2| s2.contains(c)))
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// every way of writing `total` as an ordered sum of `parts` positive sizes
+pub(super) fn compositions(total: usize, parts: usize) -> Vec<Vec<usize>> {
+    if parts == 0 {
+        return if total == 0 { vec![vec![]] } else { vec![] };
+    }
+
+    (1..=total.saturating_sub(parts - 1))
+        .flat_map(|first| {
+            compositions(total - first, parts - 1)
+                .into_iter()
+                .map(move |mut rest| {
+                    rest.insert(0, first);
+                    rest
+                })
+        })
+        .collect()
+}
+
+// One argument out of each list, every way. Unlike `multi_cartesian_product`,
+// no lists still gives one way, with no arguments.
+pub(super) fn arg_tuples<T: Clone>(options: Vec<Vec<T>>) -> impl Iterator<Item = Vec<T>> {
+    if options.is_empty() {
+        itertools::Either::Left(std::iter::once(Vec::new()))
+    } else {
+        itertools::Either::Right(options.into_iter().map(Vec::into_iter).multi_cartesian_product())
+    }
+}
 
-    bank.size_mut(size).extend(adjs);
-    // dbg!(&bank);
+fn enable_bools(examples: &[(Lit, Lit)]) -> bool {
+    examples
+        .iter()
+        .any(|(_, out)| matches!(out, Lit::BoolConst(_)))
 }
 
 pub fn top_down_vsa(examples: &[(Lit, Lit)]) -> AST {
-    top_down(examples).unwrap()
+    top_down(examples, &SynthConfig::default()).unwrap()
 }
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use itertools::Itertools;
+
+use crate::vsa::{Fun, Lit, Type};
+
+use super::grammar::{parse_lit, GrammarError};
+
+type AST = crate::vsa::AST<Lit, Fun>;
+
+// A probabilistic grammar on top of the DSL: how likely each operator and
+// constant is, relative to everything else that produces the same type. In
+// text, one symbol per line with its weight, e.g.
+//
+//     # weights only matter relative to each other
+//     fun Concat 30
+//     fun Find 12
+//     lit X 40
+//     lit " " 3
+//     lit 0 7
+//
+// Literals are written like in grammars. Anything that isn't listed is taken
+// to be half as likely as the least likely symbol of its type.
+#[derive(Debug, Clone, Default)]
+pub struct Weights {
+    weights: HashMap<Symbol, f64>,
+    // derived from `weights` once, in `from_weights`
+    bits: HashMap<Symbol, f64>,
+    unseen: HashMap<Type, f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Symbol {
+    Fun(Fun),
+    Lit(Lit),
+}
+
+impl Symbol {
+    fn ty(&self) -> Type {
+        match self {
+            Symbol::Fun(fun) => fun.ret(),
+            Symbol::Lit(lit) => lit.ty(),
+        }
+    }
+}
+
+impl Weights {
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(std::fs::read_to_string(path)?.parse()?)
+    }
+
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_string())
+    }
+
+    // Counts every operator and constant in the traces written by `datagen`.
+    // Each path from the root to a leaf is written out separately, so the
+    // arity of each operator is what tells which lines start a new node.
+    pub fn train(traces: &str) -> Result<Self, GrammarError> {
+        let mut counts = HashMap::new();
+        // how many children are left to see for each open node
+        let mut open: Vec<usize> = Vec::new();
+
+        // the depth of the next line in the current path, if past the
+        // `inp → out;` header
+        let mut depth = None;
+
+        for (i, line) in traces.lines().enumerate() {
+            let err = |msg: String| GrammarError { line: i + 1, msg };
+            if line.trim().is_empty() {
+                depth = None;
+                continue;
+            }
+            let Some(d) = depth else {
+                depth = Some(0);
+                continue;
+            };
+            depth = Some(d + 1);
+
+            // already counted with an earlier path
+            if d < open.len() {
+                continue;
+            }
+            if d > open.len() {
+                return Err(err(format!("{line} is below a leaf")));
+            }
+
+            let (_, node) = line
+                .split_once(" ← ")
+                .ok_or_else(|| err(format!("expected `out ← node`, got {line}")))?;
+            let symbol = match node.strip_prefix("Lit(").and_then(|n| n.strip_suffix(')')) {
+                Some(lit) => Symbol::Lit(parse_lit(lit).map_err(err)?),
+                None => {
+                    let name = &node[..node.find('(').unwrap_or(node.len())];
+                    Symbol::Fun(Fun::from_str(name).map_err(err)?)
+                }
+            };
+
+            match &symbol {
+                Symbol::Fun(fun) if !fun.args().is_empty() => open.push(fun.args().len()),
+                _ => {
+                    // a leaf, which finishes every node whose last child it was
+                    while let Some(left) = open.last_mut() {
+                        *left -= 1;
+                        if *left > 0 {
+                            break;
+                        }
+                        open.pop();
+                    }
+                }
+            }
+            *counts.entry(symbol).or_insert(0.0) += 1.0;
+        }
+
+        Ok(Weights::from_weights(counts))
+    }
+
+    fn from_weights(weights: HashMap<Symbol, f64>) -> Self {
+        let mut totals = HashMap::new();
+        let mut least = HashMap::new();
+        for (symbol, w) in weights.iter() {
+            *totals.entry(symbol.ty()).or_insert(0.0) += w;
+            let min = least.entry(symbol.ty()).or_insert(f64::INFINITY);
+            *min = w.min(*min);
+        }
+
+        let bits = weights
+            .iter()
+            .map(|(symbol, w)| (symbol.clone(), -(w / totals[&symbol.ty()]).log2()))
+            .collect();
+        let unseen = least
+            .into_iter()
+            .map(|(ty, min)| (ty, -(min / 2.0 / totals[&ty]).log2()))
+            .collect();
+
+        Weights {
+            weights,
+            bits,
+            unseen,
+        }
+    }
+
+    fn symbol_bits(&self, symbol: &Symbol) -> f64 {
+        match self.bits.get(symbol) {
+            Some(bits) => *bits,
+            // nothing is known about the type at all
+            None => self.unseen.get(&symbol.ty()).copied().unwrap_or(0.0),
+        }
+    }
+
+    pub fn fun_bits(&self, fun: Fun) -> f64 {
+        self.symbol_bits(&Symbol::Fun(fun))
+    }
+
+    pub fn lit_bits(&self, lit: &Lit) -> f64 {
+        self.symbol_bits(&Symbol::Lit(lit.clone()))
+    }
+
+    // -log2 of the probability of the program, so lower is more likely
+    pub fn bits(&self, ast: &AST) -> f64 {
+        match ast {
+            AST::Lit(lit) => self.lit_bits(lit),
+            AST::App { fun, args } => {
+                self.fun_bits(*fun) + args.iter().map(|arg| self.bits(arg)).sum::<f64>()
+            }
+            AST::Hole(_) => 0.0,
+        }
+    }
+}
+
+impl FromStr for Weights {
+    type Err = GrammarError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut weights = HashMap::new();
+
+        for (i, line) in s.lines().enumerate() {
+            let err = |msg: String| GrammarError { line: i + 1, msg };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (symbol, weight) = line
+                .rsplit_once(char::is_whitespace)
+                .ok_or_else(|| err(format!("expected `fun NAME WEIGHT` or `lit LIT WEIGHT`, got {line}")))?;
+            let weight = weight
+                .parse::<f64>()
+                .ok()
+                .filter(|w| *w > 0.0 && w.is_finite())
+                .ok_or_else(|| err(format!("bad weight {weight}")))?;
+
+            let symbol = match symbol.trim_end().split_once(char::is_whitespace) {
+                Some(("fun", name)) => Symbol::Fun(Fun::from_str(name.trim()).map_err(err)?),
+                Some(("lit", lit)) => Symbol::Lit(parse_lit(lit.trim()).map_err(err)?),
+                _ => return Err(err(format!("expected fun or lit, got {line}"))),
+            };
+            if weights.insert(symbol, weight).is_some() {
+                return Err(err(format!("{line} is listed twice")));
+            }
+        }
+
+        Ok(Weights::from_weights(weights))
+    }
+}
+
+// most likely first, funs before lits
+impl std::fmt::Display for Weights {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines = self
+            .weights
+            .iter()
+            .map(|(symbol, w)| match symbol {
+                Symbol::Fun(fun) => (0, format!("fun {}", fun.name()), w),
+                Symbol::Lit(lit) => (1, format!("lit {lit}"), w),
+            })
+            .sorted_by(|(a, a_name, a_w), (b, b_name, b_w)| {
+                a.cmp(b).then(b_w.total_cmp(a_w)).then(a_name.cmp(b_name))
+            });
+
+        for (_, symbol, w) in lines {
+            writeln!(f, "{symbol} {w}")?;
+        }
+        Ok(())
+    }
+}
@@ -2,8 +2,9 @@
 #![feature(local_key_cell_methods)]
 #![feature(is_some_and)]
 #![feature(adt_const_params)]
+#![allow(clippy::upper_case_acronyms)]
 
-use std::str::FromStr;
+use std::{rc::Rc, str::FromStr};
 
 use wasm_bindgen::prelude::*;
 
@@ -17,24 +18,399 @@ pub mod vsa;
 
 // pub mod datagen;
 
-use enumerative::duet;
+use enumerative::{
+    parse_sketch, CancelToken, Cegis, GrammarError, Library, RankModel, Spec, SynthConfig,
+    SynthError, SynthSession, Weights,
+};
 use js_sys::JsString;
-use vsa::Lit;
+use vsa::{arena::Arena, cursor::Cursor, Fun, Lit, AST, VSA};
 
 #[wasm_bindgen]
-pub fn synthesize(inps: Vec<JsString>, outs: Vec<JsString>, tests: Vec<JsString>) -> js_sys::Map {
-    let obj = js_sys::Map::new();
+pub fn synthesize(
+    inps: Vec<JsString>,
+    outs: Vec<JsString>,
+    tests: Vec<JsString>,
+    config: Option<SynthConfig>,
+) -> js_sys::Map {
+    let examples = examples(&inps, &outs);
+    let config = config.unwrap_or_default();
+    let (max_wrong, wrong_cost) = (config.max_wrong, config.wrong_cost);
+    let mut session = SynthSession::with_config(&examples, config);
+
+    // with typos allowed, `wrong` lists the examples that were left out and
+    // the version space is left out since it's over every example
+    if max_wrong > 0 {
+        let res = session.synthesize_noisy(max_wrong, wrong_cost).ok();
+        let wrong = res
+            .as_ref()
+            .map(|res| res.wrong.iter().map(|i| JsValue::from(*i)).collect::<js_sys::Array>());
+        let obj = result_map(res.map(|res| res.program), None, &tests);
+        if let Some(wrong) = wrong {
+            obj.set(&JsString::from_str("wrong").unwrap(), &wrong);
+        }
+        return obj;
+    }
+
+    let synthesized = session.synthesize().ok();
+    result_map(synthesized, session.version_space(), &tests)
+}
+
+// For many rows: `rows` lists the ones that ended up as examples, starting
+// with the representative ones it was given up front, even if it ran out of
+// budget before a program fit all of them
+#[wasm_bindgen]
+pub fn synthesize_cegis(
+    inps: Vec<JsString>,
+    outs: Vec<JsString>,
+    tests: Vec<JsString>,
+    config: Option<SynthConfig>,
+) -> js_sys::Map {
+    let rows = examples(&inps, &outs);
+    let config = config.unwrap_or_default();
+    let res = match enumerative::duet_cegis(&rows, &config, &CancelToken::new()) {
+        Ok(res) => Some(Cegis {
+            program: Some(res.program),
+            initial: res.initial,
+            counterexamples: res.counterexamples,
+        }),
+        Err(SynthError::Timeout { best, .. }) => best,
+        Err(SynthError::NoExamples) => None,
+    };
 
+    let used = res.as_ref().map(|res| {
+        res.initial
+            .iter()
+            .chain(res.counterexamples.iter())
+            .map(|i| JsValue::from(*i))
+            .collect::<js_sys::Array>()
+    });
+    let obj = result_map(res.and_then(|res| res.program), None, &tests);
+    if let Some(used) = used {
+        obj.set(&JsString::from_str("rows").unwrap(), &used);
+    }
+    obj
+}
+
+// Runs a synthesis and returns the VSAs it went through as JSON, see
+// `vsas_json`
+#[wasm_bindgen]
+pub fn synthesize_vsas(
+    inps: Vec<JsString>,
+    outs: Vec<JsString>,
+    config: Option<SynthConfig>,
+) -> String {
+    let examples = examples(&inps, &outs);
+    let mut session = SynthSession::with_config(&examples, config.unwrap_or_default());
+    let synthesized = session.synthesize().ok();
+    vsas_json(&mut session, synthesized)
+}
+
+fn examples(inps: &[JsString], outs: &[JsString]) -> Vec<(Lit, Lit)> {
     let inps_rs: Vec<String> = inps.iter().map(|s| s.into()).collect();
     let outs_rs: Vec<String> = outs.iter().map(|s| s.into()).collect();
-    let tests_rs: Vec<String> = tests.iter().map(|s| s.into()).collect();
-    let examples: Vec<_> = inps_rs
+    inps_rs
         .into_iter()
         .zip(outs_rs.into_iter())
         .map(|(inp, out)| (Lit::StringConst(inp), Lit::StringConst(out)))
-        .collect();
+        .collect()
+}
+
+// { "program": ..., "final": <VSA>, "examples": [<VSA>, ...] }, where each
+// VSA is in the format of `VSA::to_json` and `program` and `final` are null
+// when synthesis failed
+fn vsas_json(session: &mut SynthSession, synthesized: Option<AST<Lit, Fun>>) -> String {
+    let program = synthesized
+        .map(|prog| vsa::json_string(&prog.to_string()))
+        .unwrap_or_else(|| "null".to_string());
+    let final_vsa = session
+        .version_space()
+        .map(|vsa| vsa.to_json())
+        .unwrap_or_else(|| "null".to_string());
+    let examples = session
+        .example_vsas()
+        .iter()
+        .map(|vsa| vsa.to_json())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!(r#"{{"program":{program},"final":{final_vsa},"examples":[{examples}]}}"#)
+}
+
+// keeps the banks around between calls so the UI can add examples one at a time
+#[wasm_bindgen]
+pub struct Session(SynthSession);
+
+#[wasm_bindgen]
+impl Session {
+    #[wasm_bindgen(constructor)]
+    pub fn new(config: Option<SynthConfig>) -> Session {
+        Session(SynthSession::with_config(&[], config.unwrap_or_default()))
+    }
+
+    pub fn add_example(&mut self, inp: String, out: String) {
+        self.0
+            .add_example(Lit::StringConst(inp), Lit::StringConst(out));
+    }
+
+    pub fn remove_example(&mut self, i: usize) {
+        if i < self.0.examples().len() {
+            self.0.remove_example(i);
+        }
+    }
+
+    // `spec` is written like `prefix:ID-`, see `enumerative::Spec`
+    pub fn add_partial_example(&mut self, inp: String, spec: &str) -> Result<(), JsError> {
+        let spec = Spec::from_str(spec).map_err(|e| JsError::new(&e))?;
+        self.0.add_spec(Lit::StringConst(inp), spec);
+        Ok(())
+    }
+
+    pub fn remove_partial_example(&mut self, i: usize) {
+        if i < self.0.partial_examples().len() {
+            self.0.remove_partial(i);
+        }
+    }
+
+    // e.g. `Concat(Slice(?, ?), '@company.com')`, see `enumerative::parse_sketch`
+    pub fn set_sketch(&mut self, sketch: Option<String>) -> Result<(), JsError> {
+        let sketch = sketch
+            .map(|sketch| parse_sketch(&sketch))
+            .transpose()
+            .map_err(|e| JsError::new(&e))?;
+        self.0.set_sketch(sketch);
+        Ok(())
+    }
+
+    // for outputs the program must not produce
+    pub fn add_negative_example(&mut self, inp: String, out: String) {
+        self.0
+            .add_negative(Lit::StringConst(inp), Lit::StringConst(out));
+    }
+
+    pub fn remove_negative_example(&mut self, i: usize) {
+        if i < self.0.negatives().len() {
+            self.0.remove_negative(i);
+        }
+    }
+
+    pub fn synthesize(&mut self, tests: Vec<JsString>) -> js_sys::Map {
+        let synthesized = self.0.synthesize().ok();
+        result_map(synthesized, self.0.version_space(), &tests)
+    }
+
+    // for picking between the programs that fit the examples, see
+    // `vsa::cursor::Cursor`
+    pub fn cursor(&mut self) -> Option<VsaCursor> {
+        let config = self.0.dsl().config().clone();
+        self.0.version_space().map(|vsa| VsaCursor {
+            cursor: Cursor::new(vsa),
+            config,
+        })
+    }
+
+    // the VSAs of the last call to `synthesize`, see `vsas_json`
+    pub fn vsas(&mut self) -> String {
+        let config = self.0.dsl().config().clone();
+        let synthesized = self.0.version_space().and_then(|vsa| {
+            let mut arena = Arena::new();
+            let root = arena.import(&vsa);
+            arena.pick_best(root, |ast| config.rank(ast))
+        });
+        vsas_json(&mut self.0, synthesized)
+    }
+}
+
+#[wasm_bindgen]
+pub struct VsaCursor {
+    cursor: Cursor<Lit, Fun>,
+    config: SynthConfig,
+}
+
+#[wasm_bindgen]
+impl VsaCursor {
+    // [{ index, program, cost }], where program and cost are undefined if
+    // the alternative is empty
+    pub fn alternatives(&mut self) -> js_sys::Array {
+        self.cursor
+            .alternatives(|ast| self.config.rank(ast))
+            .into_iter()
+            .map(|alt| {
+                let obj = js_sys::Map::new();
+                obj.set(&JsValue::from_str("index"), &JsValue::from(alt.index));
+                if let (Some(best), Some(cost)) = (alt.best, alt.cost) {
+                    obj.set(&JsValue::from_str("program"), &JsValue::from(best.to_string()))
+                        .set(&JsValue::from_str("cost"), &JsValue::from(cost));
+                }
+                JsValue::from(obj)
+            })
+            .collect()
+    }
+
+    pub fn down(&mut self, i: usize) -> bool {
+        self.cursor.down(i)
+    }
+
+    pub fn up(&mut self) -> bool {
+        self.cursor.up()
+    }
+
+    pub fn pin(&mut self, i: usize) -> bool {
+        self.cursor.pin(i).is_some()
+    }
 
-    let synthesized = duet(&examples);
+    // the best program with every pinned choice
+    pub fn program(&self) -> Option<String> {
+        self.cursor
+            .best(|ast| self.config.rank(ast))
+            .map(|prog| prog.to_string())
+    }
+
+    pub fn to_json(&self) -> String {
+        self.cursor.root().to_json()
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session::new(None)
+    }
+}
+
+// the builder methods take `self` by value, which is awkward from JS
+#[wasm_bindgen]
+impl SynthConfig {
+    #[wasm_bindgen(constructor)]
+    pub fn js_new() -> SynthConfig {
+        SynthConfig::default()
+    }
+
+    pub fn from_grammar(grammar: &str) -> Result<SynthConfig, JsError> {
+        let grammar = grammar.parse().map_err(|e: GrammarError| JsError::new(&e.to_string()))?;
+        Ok(SynthConfig::default().grammar(grammar))
+    }
+
+    // in the text format of `Weights`, or `None` to go back to size order
+    pub fn set_weights(&mut self, weights: Option<String>) -> Result<(), JsError> {
+        self.weights = weights
+            .map(|w| w.parse::<Weights>())
+            .transpose()
+            .map_err(|e| JsError::new(&e.to_string()))?;
+        Ok(())
+    }
+
+    // in the text format of `Library`, which registers its functions and
+    // enables them
+    pub fn set_library(&mut self, library: String) -> Result<(), JsError> {
+        let library = library
+            .parse::<Library>()
+            .map_err(|e| JsError::new(&e.to_string()))?;
+        self.funs.extend(library.funs());
+        Ok(())
+    }
+
+    // in the text format of `RankModel`, or `None` to go back to `Cost::cost`
+    pub fn set_ranking(&mut self, ranking: Option<String>) -> Result<(), JsError> {
+        self.ranking = ranking
+            .map(|r| r.parse::<RankModel>())
+            .transpose()
+            .map_err(|e| JsError::new(&e.to_string()))?;
+        Ok(())
+    }
+
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.budget.max_size = max_size;
+    }
+
+    pub fn set_max_cost(&mut self, max_cost: Option<usize>) {
+        self.max_cost = max_cost;
+    }
+
+    pub fn set_max_wrong(&mut self, max_wrong: usize) {
+        self.max_wrong = max_wrong;
+    }
+
+    pub fn set_wrong_cost(&mut self, wrong_cost: usize) {
+        self.wrong_cost = wrong_cost;
+    }
+
+    pub fn set_enabled(&mut self, fun: &str, enabled: bool) -> Result<(), JsError> {
+        let fun = Fun::from_str(fun).map_err(|e| JsError::new(&e))?;
+        if enabled {
+            self.funs.insert(fun);
+        } else {
+            self.funs.remove(&fun);
+        }
+        Ok(())
+    }
+
+    pub fn clear_constants(&mut self) {
+        self.constants.retain(|c| !matches!(c, Lit::StringConst(_)));
+    }
+
+    pub fn add_constant(&mut self, constant: String) {
+        self.constants.push(Lit::StringConst(constant));
+    }
+
+    pub fn add_loc_constant(&mut self, constant: usize) {
+        self.constants.push(Lit::LocConst(constant));
+    }
+
+    pub fn clear_regexes(&mut self) {
+        self.regexes.clear();
+    }
+
+    pub fn add_regex(&mut self, regex: String) {
+        self.regexes.push(regex);
+    }
+
+    pub fn set_enumerate_loc_arith(&mut self, enable: bool) {
+        self.enumerate_loc_arith = enable;
+    }
+
+    pub fn set_enumerate_concats(&mut self, enable: bool) {
+        self.enumerate_concats = enable;
+    }
+
+    pub fn set_enumerate_equal(&mut self, enable: bool) {
+        self.enumerate_equal = enable;
+    }
+
+    pub fn add_hint_constant(&mut self, constant: String) {
+        self.hints.constants.push(constant);
+    }
+
+    pub fn add_hint_regex(&mut self, regex: String) {
+        self.hints.regexes.push(regex);
+    }
+
+    pub fn add_hint_fun(&mut self, fun: &str) -> Result<(), JsError> {
+        let fun = Fun::from_str(fun).map_err(|e| JsError::new(&e))?;
+        self.funs.insert(fun);
+        self.hints.funs.push(fun);
+        Ok(())
+    }
+}
+
+// `count` and `costs` say how many other programs would also have fit the
+// examples, with `costs` mapping each cost to the number of programs with it
+fn result_map(
+    synthesized: Option<AST<Lit, Fun>>,
+    version_space: Option<Rc<VSA<Lit, Fun>>>,
+    tests: &[JsString],
+) -> js_sys::Map {
+    let obj = js_sys::Map::new();
+
+    if let Some(vsa) = version_space {
+        let costs = js_sys::Map::new();
+        for (cost, n) in vsa.cost_histogram() {
+            costs.set(&JsValue::from(cost), &JsValue::from(n as f64));
+        }
+
+        obj.set(&JsString::from_str("count").unwrap(), &JsValue::from(vsa.count() as f64))
+            .set(&JsString::from_str("costs").unwrap(), &costs);
+    }
+
+    let tests_rs: Vec<String> = tests.iter().map(|s| s.into()).collect();
 
     match synthesized {
         Some(synth) => {
@@ -1,32 +1,51 @@
 #[cfg(test)]
-use crate::enumerative::top_down_vsa;
+use crate::enumerative::{duet_with_budget, Budget, CancelToken, SynthConfig};
 #[cfg(test)]
 use crate::vsa::Lit::{self, *};
+#[cfg(test)]
+use crate::vsa::{Fun, AST, VSA};
+#[cfg(test)]
+use std::rc::Rc;
 
 #[cfg(test)]
 fn test_gen(examples: Vec<(Lit, Lit)>, tests: Vec<(Lit, Lit)>) {
-    let (tx, rx) = std::sync::mpsc::channel();
-    std::thread::spawn(move || {
-        let res = top_down_vsa(&examples);
-        println!("{}, size = {}", res, res.size());
-
-        for (inp, out) in tests {
-            let evaled = res.eval(&inp);
-            let evaled = match (&inp, &evaled) {
-                (Lit::StringConst(s), Lit::LocEnd) => Lit::LocConst(s.len()),
-                _ => evaled,
-            };
-            assert_eq!(evaled, out);
-        }
+    let budget = Budget::default().timeout(std::time::Duration::from_secs(12));
+    let config = SynthConfig::default().budget(budget);
+    let res = match duet_with_budget(&examples, &config, &CancelToken::new()) {
+        Ok(res) => res,
+        Err(e) => panic!("{e}"),
+    };
+    println!("{}, size = {}", res, res.size());
 
-        tx.send(()).unwrap();
-    });
+    for (inp, out) in tests {
+        let evaled = res.eval(&inp);
+        let evaled = match (&inp, &evaled) {
+            (Lit::StringConst(s), Lit::LocEnd) => Lit::LocConst(s.len()),
+            _ => evaled,
+        };
+        assert_eq!(evaled, out);
+    }
+}
 
-    if let Err(_) = rx.recv_timeout(std::time::Duration::from_secs(12)) {
-        panic!("timeout");
+// Fixtures for VSAs built by hand
+#[cfg(test)]
+fn lit(s: &str) -> AST<Lit, Fun> {
+    AST::Lit(StringConst(s.to_string()))
+}
+
+#[cfg(test)]
+fn concat(a: AST<Lit, Fun>, b: AST<Lit, Fun>) -> AST<Lit, Fun> {
+    AST::App {
+        fun: Fun::Concat,
+        args: vec![a, b],
     }
 }
 
+#[cfg(test)]
+fn leaf(asts: Vec<AST<Lit, Fun>>) -> Rc<VSA<Lit, Fun>> {
+    Rc::new(VSA::Leaf(asts.into_iter().map(Rc::new).collect()))
+}
+
 macro_rules! test {
     ($name:ident, $out_mapper:expr, $($inp:expr => $out:expr),+; $($test_inp:expr => $test_out:expr),+) => {
         #[test]
@@ -242,3 +261,987 @@ test! {
 //     "abc" => true,
 //     "1234567890" => false
 // }
+
+#[test]
+fn test_session() {
+    use crate::enumerative::SynthSession;
+
+    let s = |s: &str| StringConst(s.to_string());
+    let mut session = SynthSession::new(&[(s("I have 17 cookies"), s("17"))]);
+    assert!(session.synthesize().is_ok());
+
+    session.add_example(s("Give me at least 3 cookies"), s("3"));
+    session.add_example(s("This number is 489"), s("489"));
+    let prog = session.synthesize().unwrap();
+    assert_eq!(prog.eval(&s("Number at the end 74")), s("74"));
+
+    session.add_example(s("oops"), s("not a number"));
+    session.remove_example(3);
+    let prog = session.synthesize().unwrap();
+    assert_eq!(prog.eval(&s("36")), s("36"));
+}
+
+#[test]
+fn test_budget() {
+    use crate::enumerative::{Exhausted, SynthError};
+
+    let examples = vec![
+        (StringConst("short /no/ line".to_string()), StringConst("short  line".to_string())),
+        (StringConst("aa/aa/aa".to_string()), StringConst("aaaa".to_string())),
+    ];
+
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    assert!(matches!(
+        duet_with_budget(&examples, &SynthConfig::default(), &cancel),
+        Err(SynthError::Timeout { reason: Exhausted::Cancelled, .. })
+    ));
+
+    // nothing fits these, so only the deadline can stop it
+    let examples = vec![
+        (StringConst("a b c".to_string()), StringConst("x.y".to_string())),
+        (StringConst("d e f".to_string()), StringConst("q.r".to_string())),
+    ];
+    let budget = Budget::default()
+        .timeout(std::time::Duration::from_millis(200))
+        .max_size(50);
+    let config = SynthConfig::default().budget(budget);
+    let start = std::time::Instant::now();
+    assert!(matches!(
+        duet_with_budget(&examples, &config, &CancelToken::new()),
+        Err(SynthError::Timeout { reason: Exhausted::Deadline, .. })
+    ));
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+
+    // the timeout that ran out above starts over for a new call
+    let examples = vec![
+        (StringConst("ab cd".to_string()), StringConst("cd".to_string())),
+        (StringConst("hello world".to_string()), StringConst("world".to_string())),
+    ];
+    assert!(duet_with_budget(&examples, &config, &CancelToken::new()).is_ok());
+}
+
+#[test]
+fn test_config() {
+    use crate::vsa::Fun;
+
+    let s = |s: &str| StringConst(s.to_string());
+    let examples = [(s("Hello"), s("Hello World")), (s("Goodbye"), s("Goodbye World"))];
+
+    // without Concat the only way out is a constant, which can't fit both
+    let config = SynthConfig::default().disable(Fun::Concat).max_size(3);
+    assert!(crate::enumerative::duet(&examples, &config).is_none());
+
+    let config = SynthConfig::default().constant(s(" World"));
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    assert_eq!(prog.eval(&s("B")), s("B World"));
+}
+
+#[test]
+fn test_hints() {
+    let s = |s: &str| StringConst(s.to_string());
+    let examples = [
+        (s("order ABC123 shipped"), s("ABC123")),
+        (s("XYZ98765 was late"), s("XYZ98765")),
+    ];
+
+    let config = SynthConfig::default().hint_regex("[A-Z]{3}\\d+");
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    println!("{prog}");
+    assert_eq!(prog.eval(&s("see QQQ1 now")), s("QQQ1"));
+}
+
+#[test]
+fn test_find_witness() {
+    let s = |s: &str| StringConst(s.to_string());
+    let examples = [
+        (s("bob <bob@x.com>"), s("bob@x.com")),
+        (s("alice smith <a@y.org>"), s("a@y.org")),
+    ];
+
+    // too small for bottom up to build any Find, so the positions have to
+    // come from the witness
+    let config = SynthConfig::default().max_size(2);
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    println!("{prog}");
+    assert_eq!(prog.eval(&s("eve <e@z.net>")), s("e@z.net"));
+}
+
+#[test]
+fn test_noisy() {
+    use crate::enumerative::duet_noisy;
+    use crate::vsa::{custom::{self, CustomFun}, Type};
+
+    let s = |s: &str| StringConst(s.to_string());
+    let examples = [
+        (s("John Smith"), s("Smith, John")),
+        (s("Jane Doe"), s("Doe, Jane")),
+        (s("Alan Turing"), s("Turnig, Alan")),
+        (s("Ada Lovelace"), s("Lovelace, Ada")),
+    ];
+
+    let config = SynthConfig::default().max_size(4);
+    assert!(crate::enumerative::duet(&examples, &config).is_none());
+
+    let config = config.max_wrong(1);
+    let res = duet_noisy(&examples, &config, &CancelToken::new()).unwrap();
+    println!("{}", res.program);
+    assert_eq!(res.wrong, vec![2]);
+    assert_eq!(res.program.eval(&s("Grace Hopper")), s("Hopper, Grace"));
+
+    // nothing to leave out
+    let res = duet_noisy(&examples[..2], &config, &CancelToken::new()).unwrap();
+    assert!(res.wrong.is_empty());
+
+    // `X` only gets the last one wrong, and reversing it with slices costs
+    // more than that, but the bank can still grow to where `backwards` is
+    let rev = |s: &Lit| match s {
+        StringConst(s) => StringConst(s.chars().rev().collect()),
+        _ => panic!(),
+    };
+    let backwards = CustomFun::new("backwards", vec![Type::String], Type::String, move |args| {
+        rev(&args[0])
+    });
+    let config = SynthConfig::default().enable(custom::register(backwards).unwrap()).max_wrong(1);
+    let examples = [(s("aba"), s("aba")), (s("xyx"), s("xyx")), (s("abc"), s("cba"))];
+    let res = duet_noisy(&examples, &config, &CancelToken::new()).unwrap();
+    println!("{}", res.program);
+    assert!(res.wrong.is_empty());
+    assert_eq!(res.program.eval(&s("stressed")), s("desserts"));
+}
+
+#[test]
+fn test_negatives() {
+    use crate::enumerative::{duet_with_negatives, Exhausted, SynthError, SynthSession};
+
+    let s = |s: &str| StringConst(s.to_string());
+    let examples = [(s("abc 12"), s("12"))];
+    let config = SynthConfig::default();
+
+    // one example is enough for the constant
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    assert_eq!(prog.eval(&s("xyz 99")), s("12"));
+
+    // but it isn't always 12
+    let negatives = [(s("xyz 99"), s("12"))];
+    let prog = duet_with_negatives(&examples, &negatives, &config).unwrap();
+    println!("{prog}");
+    assert_eq!(prog.eval(&s("abc 12")), s("12"));
+    assert_ne!(prog.eval(&s("xyz 99")), s("12"));
+    assert_eq!(prog.eval(&s("def 34")), s("34"));
+
+    let mut session = SynthSession::with_config(&examples, config);
+    session.add_negative(s("xyz 99"), s("12"));
+    let prog = session.synthesize().unwrap();
+    assert_eq!(prog.eval(&s("abc 12")), s("12"));
+    assert_ne!(prog.eval(&s("xyz 99")), s("12"));
+    session.remove_negative(0);
+    assert_eq!(session.synthesize().unwrap().eval(&s("xyz 99")), s("12"));
+
+    // turning down '12' is already too many
+    let config = SynthConfig::default().budget(Budget::default().max_rejected(0));
+    let mut session = SynthSession::with_config(&examples, config);
+    session.add_negative(s("xyz 99"), s("12"));
+    assert!(matches!(
+        session.synthesize(),
+        Err(SynthError::Timeout { reason: Exhausted::Rejected, .. })
+    ));
+}
+
+#[test]
+fn test_specs() {
+    use crate::enumerative::{duet_with_specs, Spec};
+
+    let s = |s: &str| StringConst(s.to_string());
+    assert!(Spec::Prefix("ID-".to_string()).matches(&s("ID-42")));
+    assert!(Spec::Regex("\\d{4}".to_string()).matches(&s("2024")));
+    assert!(!Spec::Regex("\\d{4}".to_string()).matches(&s("12024")));
+    assert_eq!("length:1-5".parse(), Ok(Spec::Length(1, 5)));
+    assert_eq!("prefix:a:b".parse(), Ok(Spec::Prefix("a:b".to_string())));
+    assert!("regex:(\\d".parse::<Spec>().is_err());
+    assert!("length:5-2".parse::<Spec>().is_err());
+
+    // only the first output is known exactly, the rest just has to look right
+    let examples = [
+        (s("ab 12"), Spec::Exact("ID-12".to_string())),
+        (s("xyz 345"), Spec::Length(6, 6)),
+        (s("q 7"), Spec::Regex("ID-\\d+".to_string())),
+    ];
+    let prog = duet_with_specs(&examples, &SynthConfig::default()).unwrap();
+    println!("{prog}");
+    for (inp, spec) in examples.iter() {
+        assert!(spec.matches(&prog.eval(inp)));
+    }
+    assert_eq!(prog.eval(&s("hello 99")), s("ID-99"));
+
+    // without anything exact, it's learned from the bank and the suffix witness
+    let examples = [
+        (s("abc"), Spec::Suffix("!".to_string())),
+        (s("de"), Spec::Length(3, 3)),
+    ];
+    let prog = duet_with_specs(&examples, &SynthConfig::default()).unwrap();
+    println!("{prog}");
+    for (inp, spec) in examples.iter() {
+        assert!(spec.matches(&prog.eval(inp)));
+    }
+}
+
+#[test]
+fn test_sketch() {
+    use crate::enumerative::{complete_sketch, parse_sketch};
+    use crate::vsa::{Type, AST};
+
+    let s = |s: &str| StringConst(s.to_string());
+    let sketch = parse_sketch("Concat(Slice(?, ?), '@company.com')").unwrap();
+    assert_eq!(sketch.holes(), vec![Type::Loc, Type::Loc]);
+    assert_eq!(parse_sketch("?").unwrap(), AST::Hole(Type::String));
+    assert!(parse_sketch("Slice(?, 'a')").is_err());
+    assert!(parse_sketch("Concat(?)").is_err());
+
+    let examples = [
+        (s("jsmith 123"), s("jsmith@company.com")),
+        (s("alee 42"), s("alee@company.com")),
+    ];
+    let prog = complete_sketch(&sketch, &examples, &SynthConfig::default()).unwrap();
+    println!("{prog}");
+    assert!(prog.completes(&sketch));
+    assert_eq!(prog.eval(&s("bob 7")), s("bob@company.com"));
+
+    // one example on its own gives a constant, unless the sketch says otherwise
+    let examples = [(s("abc"), s("a"))];
+    let prog = crate::enumerative::duet(&examples, &SynthConfig::default()).unwrap();
+    assert_eq!(prog.to_string(), "'a'");
+    let sketch = parse_sketch("Slice(?, ?)").unwrap();
+    let prog = complete_sketch(&sketch, &examples, &SynthConfig::default()).unwrap();
+    assert_eq!(prog.eval(&s("xyz")), s("x"));
+}
+
+#[test]
+fn test_arena() {
+    use crate::vsa::arena::Arena;
+
+    let join = VSA::Join {
+        op: Fun::Concat,
+        children: vec![leaf(vec![lit("a")]), leaf(vec![lit("b"), lit("c")])],
+    };
+
+    // the arguments have to match position by position
+    let swapped = VSA::Leaf(
+        [concat(lit("a"), lit("c")), concat(lit("c"), lit("a"))]
+            .into_iter()
+            .map(Rc::new)
+            .collect(),
+    );
+    let res = join.intersect(&swapped);
+    assert_eq!(res.pick_one(), Some(concat(lit("a"), lit("c"))));
+    assert!(!res.contains(&concat(lit("c"), lit("a"))));
+
+    let mut arena = Arena::new();
+    let a = arena.import(&join);
+    let b = arena.import(&join.clone());
+    assert_eq!(a, b);
+    assert_eq!(arena.intersect(a, b), a);
+
+    // nested unions are flattened, so unifying in a different order gives
+    // the same node
+    let (x, y, z) = (leaf(vec![lit("x")]), Rc::new(join.clone()), leaf(vec![lit("z")]));
+    let l = VSA::unify(Rc::new(VSA::unify(x.clone(), y.clone())), z.clone());
+    let r = VSA::unify(Rc::new(VSA::unify(z, y)), x);
+    assert_eq!(arena.import(&l), arena.import(&r));
+}
+
+#[test]
+fn test_count() {
+    use crate::enumerative::SynthSession;
+
+    // "a" or "bb", twice over
+    let arg = leaf(vec![lit("a"), lit("bb")]);
+    let join = Rc::new(VSA::Join {
+        op: Fun::Concat,
+        children: vec![arg.clone(), arg],
+    });
+    let vsa = VSA::Union(vec![join, leaf(vec![lit("c")])]);
+    assert_eq!(vsa.count(), 5);
+    assert_eq!(vsa.size_histogram(), [(1, 1), (3, 4)].into());
+    assert_eq!(vsa.cost_histogram(), [(1, 1), (4, 4)].into());
+
+    // another example can only narrow things down
+    let s = |s: &str| StringConst(s.to_string());
+    let mut session = SynthSession::new(&[(s("01/15/2013"), s("01/2013"))]);
+    session.synthesize().unwrap();
+    let before = session.version_space().unwrap().count();
+    session.add_example(s("03/07/2011"), s("03/2011"));
+    session.synthesize().unwrap();
+    let after = session.version_space().unwrap().count();
+    assert!(after > 0 && after < before, "{before} -> {after}");
+}
+
+#[test]
+fn test_minimize() {
+    let dead = Rc::new(VSA::Join {
+        op: Fun::Concat,
+        children: vec![leaf(vec![lit("a")]), Rc::new(VSA::empty())],
+    });
+    let nested = Rc::new(VSA::Union(vec![leaf(vec![lit("a")]), dead]));
+    let vsa = VSA::Union(vec![nested, leaf(vec![lit("a"), lit("b")])]);
+    let min = vsa.minimize();
+    assert!(matches!(min.as_ref(), VSA::Leaf(asts) if asts.len() == 2));
+
+    // Concat costs 4 here, everything else 1
+    let arg = leaf(vec![lit("a"), lit("bb")]);
+    let join = Rc::new(VSA::Join {
+        op: Fun::Concat,
+        children: vec![arg.clone(), arg],
+    });
+    let vsa = VSA::Union(vec![join, leaf(vec![lit("c")])]);
+    assert_eq!(vsa.prune(4).count(), 5);
+    assert_eq!(vsa.prune(3).count(), 1);
+
+    let s = |s: &str| StringConst(s.to_string());
+    let examples = [
+        (s("01/15/2013"), s("01/2013")),
+        (s("03/07/2011"), s("03/2011")),
+    ];
+    let config = SynthConfig::default().max_cost(8);
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    assert_eq!(prog.eval(&s("09/02/07")), s("09/07"));
+}
+
+#[test]
+fn test_export() {
+    use crate::vsa::{Fun, AST, VSA};
+    use std::rc::Rc;
+
+    let arg = Rc::new(VSA::Leaf(
+        [AST::Lit(Input), AST::Lit(StringConst("a\"".to_string()))]
+            .into_iter()
+            .map(Rc::new)
+            .collect(),
+    ));
+    let vsa = VSA::Join {
+        op: Fun::Concat,
+        children: vec![arg.clone(), arg],
+    };
+
+    assert_eq!(
+        vsa.to_json(),
+        concat!(
+            r#"{"root":0,"nodes":[{"id":0,"kind":"join","op":"Concat"},"#,
+            r#"{"id":1,"kind":"leaf","programs":["'a\"'","X"]}],"#,
+            r#""edges":[{"from":0,"to":1,"index":0},{"from":0,"to":1,"index":1}]}"#
+        )
+    );
+    assert_eq!(
+        vsa.to_dot(),
+        concat!(
+            "digraph vsa {\n",
+            "    n0 [shape=ellipse, label=\"Concat\"];\n",
+            "    n1 [shape=box, label=\"'a\\\"'\\nX\"];\n",
+            "    n0 -> n1 [label=\"0\"];\n",
+            "    n0 -> n1 [label=\"1\"];\n",
+            "}\n"
+        )
+    );
+}
+
+#[test]
+fn test_cursor() {
+    use crate::vsa::{cursor::Cursor, Cost};
+
+    let rank = |ast: &AST<Lit, Fun>| ast.cost();
+
+    let join = Rc::new(VSA::Join {
+        op: Fun::Concat,
+        children: vec![leaf(vec![AST::Lit(Input)]), leaf(vec![lit("a"), lit("b")])],
+    });
+    let mut cursor = Cursor::new(Rc::new(VSA::Union(vec![join, leaf(vec![lit("z")])])));
+    assert_eq!(cursor.best(rank), Some(lit("z")));
+
+    let alts = cursor.alternatives(rank);
+    assert_eq!(alts.len(), 2);
+    assert_eq!(alts[0].cost, Some(4));
+    assert_eq!((alts[1].best.clone(), alts[1].cost), (Some(lit("z")), Some(1)));
+
+    // take the concat, then its second constant
+    cursor.pin(0).unwrap();
+    assert!(cursor.pin(0).is_none());
+    assert!(cursor.down(1));
+    assert_eq!(cursor.alternatives(rank).len(), 2);
+    cursor.pin(1).unwrap();
+    assert_eq!(cursor.best(rank).unwrap().to_string(), "(X <> 'b')");
+    assert_eq!(cursor.root().count(), 1);
+
+    assert!(cursor.up());
+    assert!(!cursor.up());
+
+    // every level shares its child twice, so picking has to be memoized
+    let mut shared = leaf(vec![lit("a")]);
+    for _ in 0..40 {
+        shared = Rc::new(VSA::Union(vec![shared.clone(), shared]));
+    }
+    let mut cursor = Cursor::new(shared);
+    assert_eq!(cursor.best(rank), Some(lit("a")));
+    assert_eq!(cursor.alternatives(rank)[1].best, Some(lit("a")));
+}
+
+#[test]
+fn test_algebra() {
+    use crate::vsa::Symbol;
+
+    let join = Rc::new(VSA::Join {
+        op: Fun::Concat,
+        children: vec![leaf(vec![AST::Lit(Input), lit("a")]), leaf(vec![lit("b"), lit("c")])],
+    });
+    let vsa = VSA::Union(vec![join, leaf(vec![lit("z")])]);
+
+    let rejected = leaf(vec![concat(AST::Lit(Input), lit("b")), lit("z")]);
+    let rest = vsa.difference(&rejected);
+    assert!(!rest.contains(&concat(AST::Lit(Input), lit("b"))));
+    assert!(!rest.contains(&lit("z")));
+    assert!(rest.contains(&concat(AST::Lit(Input), lit("c"))));
+    assert!(rest.contains(&concat(lit("a"), lit("b"))));
+
+    assert_eq!(vsa.filter(|sym| sym != Symbol::Fun(Fun::Concat)).count(), 1);
+    let a = StringConst("a".to_string());
+    assert_eq!(vsa.filter(|sym| sym != Symbol::Lit(&a)).count(), 3);
+
+    let rhs = vsa.project(Fun::Concat, 1);
+    assert_eq!(rhs.count(), 2);
+    assert!(rhs.contains(&lit("c")));
+}
+
+#[test]
+fn test_grammar() {
+    use crate::bank::Bank;
+    use crate::enumerative::{Dsl, Grammar, StringDsl};
+    use crate::vsa::{custom::{self, CustomFun}, Type, AST};
+
+    let s = |s: &str| StringConst(s.to_string());
+    let grammar = Grammar::load("data/strings.grammar").unwrap();
+    assert_eq!(grammar.cost(crate::vsa::Fun::Concat), Some(2));
+
+    let config = SynthConfig::default().grammar(grammar);
+    let examples = [
+        (s("I have 17 cookies"), s("17")),
+        (s("Give me at least 3 cookies"), s("3")),
+        (s("This number is 489"), s("489")),
+    ];
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    assert_eq!(prog.eval(&s("Number at the end 74")), s("74"));
+
+    // no Find, so only constant slices are left
+    let grammar: Grammar = "
+        nonterminal S : string
+        nonterminal L : loc
+        S := Slice(L, L)
+        L := 0 | 1 | 2 | 3
+    "
+    .parse()
+    .unwrap();
+    let config = SynthConfig::default().grammar(grammar);
+    let examples = [(s("abcdef"), s("bc")), (s("uvwxyz"), s("vw"))];
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    assert_eq!(prog.to_string(), "(X[1..3])");
+
+    // the same small programs as the hard-coded enumeration, without
+    // dropping any for giving the same outputs
+    let programs = |config: SynthConfig| {
+        let mut dsl = StringDsl::new(config);
+        let mut bank = Bank::new();
+        bank.grow_to(1);
+        let seeds = dsl.seeds(&examples);
+        bank.size_mut(1).extend(seeds.into_iter().map(AST::Lit));
+        for size in 2..=4 {
+            bank.grow_to(size);
+            let programs = dsl.enumerate(size, &bank).collect::<Vec<_>>();
+            bank.size_mut(size).extend(programs);
+        }
+        bank.entries
+            .into_iter()
+            .flatten()
+            .filter(|prog| prog.size() <= 4)
+            .collect::<std::collections::HashSet<_>>()
+    };
+    let grammar = Grammar::load("data/strings.grammar").unwrap();
+    let default = programs(SynthConfig::default());
+    assert!(default.iter().any(|prog| prog.to_string().ends_with("'+')")));
+    assert_eq!(default, programs(SynthConfig::default().grammar(grammar)));
+
+    // calls without arguments are programs of size 2
+    let dash = CustomFun::new("dash", vec![], Type::String, |_| StringConst("-".to_string()));
+    let dash = custom::register(dash).unwrap();
+    let dashes = programs(SynthConfig::default().enable(dash));
+    assert!(dashes.iter().any(|prog| prog.to_string() == "dash()"));
+    let grammar: Grammar = "
+        nonterminal S : string
+        S := Concat(S, S) | dash()
+        S := X
+    "
+    .parse()
+    .unwrap();
+    let dashes = programs(SynthConfig::default().grammar(grammar));
+    assert!(dashes.iter().any(|prog| prog.to_string() == "dash()"));
+
+    let err = "nonterminal S : string\nS := Slice(S, S)".parse::<Grammar>();
+    assert_eq!(err.unwrap_err().line, 2);
+    let err = "nonterminal S : string\nS := Nope(S)".parse::<Grammar>();
+    assert_eq!(err.unwrap_err().msg, "unknown function Nope");
+}
+
+#[test]
+fn test_weights() {
+    use crate::bank::Bank;
+    use crate::enumerative::{Dsl, StringDsl, Weights};
+    use crate::vsa::{Fun, AST};
+
+    let s = |s: &str| StringConst(s.to_string());
+
+    // Concat(X, " ") on one input, the way datagen writes it
+    let traces = "\"a\" → \"a \";\n\"a \" ← Concat(\"a\", \" \")\n\"a\" ← Lit(X)\n\n\
+                  \"a\" → \"a \";\n\"a \" ← Concat(\"a\", \" \")\n\" \" ← Lit(\" \")\n\n";
+    let weights = Weights::train(traces).unwrap();
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+    assert!(close(weights.fun_bits(Fun::Concat), 3f64.log2()));
+    assert!(close(weights.lit_bits(&s(" ")), 3f64.log2()));
+    // half as likely as the least likely string
+    assert!(close(weights.lit_bits(&s(".")), 6f64.log2()));
+
+    let prog = AST::App {
+        fun: Fun::Concat,
+        args: vec![AST::Lit(Input), AST::Lit(s("."))],
+    };
+    let parsed: Weights = weights.to_string().parse().unwrap();
+    assert_eq!(parsed.bits(&prog), weights.bits(&prog));
+    assert_eq!("fun Concat 0".parse::<Weights>().unwrap_err().msg, "bad weight 0");
+
+    let weights = Weights::load("data/strings.weights").unwrap();
+    let mut dsl = StringDsl::new(SynthConfig::default().weights(weights.clone()));
+    let examples = [(s("ab cd"), s("cd")), (s("xy z"), s("z"))];
+    let mut bank = Bank::new();
+    bank.grow_to(1);
+    let seeds = dsl.seeds(&examples);
+    bank.size_mut(1).extend(seeds.into_iter().map(AST::Lit));
+
+    let bits = dsl.enumerate(3, &bank).map(|ast| weights.bits(&ast)).collect::<Vec<_>>();
+    assert!(!bits.is_empty());
+    assert!(bits.windows(2).all(|w| w[0] <= w[1]));
+
+    let config = SynthConfig::default().weights(weights);
+    let examples = [(s("ab cd"), s("cd")), (s("hello world"), s("world"))];
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    assert_eq!(prog.eval(&s("foo barbaz")), s("barbaz"));
+
+    // the likeliest of the programs that fit is picked
+    let examples = [(s("abc 12"), s("12")), (s("wxyz 345"), s("345"))];
+    let default = crate::enumerative::duet(&examples, &SynthConfig::default()).unwrap();
+    let weights: Weights = "fun Slice 10\nfun FindEnd 10\nfun Find 1\n\
+                            lit X 10\nlit \" \" 10\nlit \"\\d\" 1\nlit 0 10\nlit $ 10"
+        .parse()
+        .unwrap();
+    let config = SynthConfig::default().weights(weights.clone());
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    assert!(examples.iter().all(|(inp, out)| prog.eval(inp) == *out));
+    assert_eq!(prog.eval(&s("q 6789")), s("6789"));
+    assert!(weights.bits(&prog) < weights.bits(&default), "{prog} vs {default}");
+
+    // and the ones datagen trains work too
+    let config = SynthConfig::default().weights(Weights::load("data/weights.txt").unwrap());
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    assert_eq!(prog.eval(&s("q 6789")), s("6789"));
+}
+
+#[test]
+fn test_ranking() {
+    use crate::enumerative::{RankModel, RankingTask};
+    use crate::vsa::{Fun, AST};
+
+    let s = |s: &str| StringConst(s.to_string());
+    let app = |fun, args| AST::App { fun, args };
+
+    // everything after the first space
+    let program = app(
+        Fun::Slice,
+        vec![
+            app(
+                Fun::FindEnd,
+                vec![AST::Lit(Input), AST::Lit(s(" ")), AST::Lit(LocConst(0))],
+            ),
+            AST::Lit(LocEnd),
+        ],
+    );
+    let task = |inp: &str| RankingTask {
+        examples: vec![(s(inp), program.eval(&s(inp)))],
+        tests: vec![s("hello world"), s("x yz")],
+        program: program.clone(),
+    };
+
+    // a single example is always best explained by a constant
+    let config = SynthConfig::default();
+    let examples = [(s("ab cd"), s("cd"))];
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    assert_eq!(prog, AST::Lit(s("cd")));
+
+    let tasks = [task("ab cd"), task("one two"), task("a b")];
+    let (model, skipped) = RankModel::fit(&tasks, &config, 20, 0.1);
+    assert_eq!(skipped, 0);
+    assert!(model.weight("const:len") > 0.0 || model.weight("const:string") > 0.0);
+
+    let parsed: RankModel = model.to_string().parse().unwrap();
+    assert_eq!(parsed.rank(&program), model.rank(&program));
+    assert!("node 1\nfun:Concat -1".parse::<RankModel>().is_err());
+    assert!("node 0.01".parse::<RankModel>().is_err());
+    assert!("fun:Concat 1".parse::<RankModel>().is_err());
+
+    let config = SynthConfig::default().ranking(model);
+    let examples = [(s("uv wxyz"), s("wxyz"))];
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    assert_eq!(prog.eval(&s("foo bar")), s("bar"));
+
+    // both fit, and a regex is picked over a space by default, which only
+    // shows on an input with a digit before the space
+    let examples = [(s("abc 12"), s("12")), (s("wxyz 345"), s("345"))];
+    let prog = crate::enumerative::duet(&examples, &SynthConfig::default()).unwrap();
+    assert_eq!(prog.eval(&s("ab1 2")), s("1 2"));
+    let model: RankModel = "node 1\nregex:class 2\nregex:boundary 2".parse().unwrap();
+    let prog = crate::enumerative::duet(&examples, &SynthConfig::default().ranking(model)).unwrap();
+    assert_eq!(prog.eval(&s("ab1 2")), s("2"));
+
+    // which is learned from tasks where the space was intended
+    let task = |a: &str, b: &str| RankingTask {
+        examples: vec![(s(a), program.eval(&s(a))), (s(b), program.eval(&s(b)))],
+        tests: vec![s("hello world"), s("x yz")],
+        program: program.clone(),
+    };
+    let tasks = [task("abc 12", "wxyz 345"), task("a 1", "bc 23")];
+    let (model, _) = RankModel::fit(&tasks, &SynthConfig::default(), 20, 0.1);
+    assert!(model.weight("regex:class") > 0.0);
+    let prog = crate::enumerative::duet(&examples, &SynthConfig::default().ranking(model)).unwrap();
+    assert_eq!(prog.eval(&s("ab1 2")), s("2"));
+}
+
+#[test]
+fn test_mcmc() {
+    use crate::enumerative::duet_mcmc;
+
+    let s = |s: &str| StringConst(s.to_string());
+    let examples = [(s("ab"), s("ab-ab")), (s("xyz"), s("xyz-xyz"))];
+
+    // too small a budget for duet to find anything itself
+    let config = SynthConfig::default().budget(Budget::default().max_vsa_nodes(1));
+    let prog = duet_mcmc(&examples, &config, &CancelToken::new()).unwrap();
+    println!("{prog}");
+    assert!(examples.iter().all(|(inp, out)| prog.eval(inp) == *out));
+    assert_eq!(prog.eval(&s("hello")), s("hello-hello"));
+    assert_eq!(prog.eval(&s("")), s("-"));
+
+    let mcmc = crate::enumerative::Mcmc {
+        steps: 0,
+        ..Default::default()
+    };
+    let config = config.mcmc(mcmc);
+    match duet_mcmc(&examples, &config, &CancelToken::new()) {
+        Err(crate::enumerative::SynthError::Timeout { best: Some(best), .. }) => {
+            assert_eq!(best, crate::vsa::AST::Lit(Input))
+        }
+        res => panic!("{res:?}"),
+    }
+}
+
+#[test]
+fn test_abstract() {
+    use crate::bank::Bank;
+    use crate::enumerative::{parse_sketch, Abstract, Dsl, StringDsl, Witness};
+    use crate::vsa::{Fun, AST};
+
+    let s = |s: &str| StringConst(s.to_string());
+
+    let sketch = parse_sketch("Concat(X, ?)").unwrap();
+    let abs = Abstract::eval(&sketch, &s("abc")).unwrap();
+    assert!(!abs.admits("ab") && abs.admits("abc!"));
+    let abs = Abstract::eval(&parse_sketch("Slice(?, ?)").unwrap(), &s("abc")).unwrap();
+    assert!(!abs.admits("abz") && abs.admits("ca"));
+    let abs = Abstract::eval(&parse_sketch("Uppercase(Concat(X, ?))").unwrap(), &s("abc")).unwrap();
+    assert!(!abs.admits("abc") && abs.admits("ABC?"));
+
+    // nothing but constants can output a D or an r, so splitting "Dr" only
+    // gives worse programs than the constant
+    let examples = [(s("smith"), s("Dr smith"))];
+    let mut dsl = StringDsl::new(SynthConfig::default());
+    let mut bank = Bank::new();
+    bank.grow_to(1);
+    let seeds = dsl.seeds(&examples);
+    bank.size_mut(1).extend(seeds.into_iter().map(AST::Lit));
+    assert!(Fun::Concat.witness(&s("smith"), &s("Dr"), &dsl, &bank).is_empty());
+    assert_eq!(Fun::Concat.witness(&s("smith"), &s("Dr "), &dsl, &bank).len(), 2);
+
+    // which only holds when programs are ranked by their cost, and a hinted
+    // constant is free
+    let weighted = StringDsl::new(SynthConfig::default().hint_constant("D"));
+    assert_eq!(Fun::Concat.witness(&s("smith"), &s("Dr"), &weighted, &bank).len(), 1);
+
+    let prog = crate::enumerative::duet(&examples, &SynthConfig::default()).unwrap();
+    assert_eq!(prog.eval(&s("smith")), s("Dr smith"));
+
+    // X alone is already too long
+    let examples = [(s("abcdef"), s("abc"))];
+    assert_eq!(crate::enumerative::complete_sketch(&sketch, &examples, &SynthConfig::default()), None);
+}
+
+#[test]
+fn test_cegis() {
+    use crate::enumerative::duet_cegis;
+
+    let s = |s: &str| StringConst(s.to_string());
+    let names = [
+        "Ada Lovelace", "Alan Turing", "Grace Hopper", "Edsger Dijkstra", "Barbara Liskov",
+        "Donald Knuth", "Frances Allen", "John McCarthy", "Tony Hoare", "Niklaus Wirth",
+        "Robin Milner", "Alonzo Church", "Haskell Curry", "Kurt Godel",
+    ];
+    let mut rows = names
+        .iter()
+        .map(|name| (s(name), s(name.split(' ').next().unwrap())))
+        .collect::<Vec<_>>();
+    rows.push((s("John Ronald Reuel Tolkien"), s("John")));
+
+    let config = SynthConfig::default().cegis_initial(1);
+    let res = duet_cegis(&rows, &config, &CancelToken::new()).unwrap();
+    println!("{} from {:?} and {:?}", res.program, res.initial, res.counterexamples);
+
+    // one example on its own gives the constant 'Ada', but nowhere near all
+    // the rows are needed
+    assert_eq!(res.initial, vec![0]);
+    assert!(!res.counterexamples.is_empty());
+    assert!(res.initial.len() + res.counterexamples.len() < 5);
+
+    // the first of each shape
+    let config = SynthConfig::default();
+    let res = duet_cegis(&rows, &config, &CancelToken::new()).unwrap();
+    assert_eq!(res.initial, vec![0, rows.len() - 1]);
+    for (inp, out) in rows.iter() {
+        assert_eq!(res.program.eval(inp), *out);
+    }
+
+    // nothing small enough outputs Q#% as well, but what was found on the
+    // way there is kept
+    let rows = [
+        (s("Ada Lovelace"), s("Ada")),
+        (s("Alan Turing"), s("Alan")),
+        (s("xyz"), s("Q#%")),
+    ];
+    let config = SynthConfig::default().cegis_initial(1).max_size(3);
+    match duet_cegis(&rows, &config, &CancelToken::new()) {
+        Err(crate::enumerative::SynthError::Timeout { best: Some(res), .. }) => {
+            assert_eq!((res.initial, res.counterexamples), (vec![0], vec![1, 2]));
+            let best = res.program.unwrap();
+            assert!(rows[..2].iter().all(|(inp, out)| best.eval(inp) == *out));
+        }
+        res => panic!("{res:?}"),
+    }
+}
+
+#[test]
+fn test_library() {
+    use crate::enumerative::{parse_sketch, Library};
+
+    let s = |s: &str| StringConst(s.to_string());
+    let programs = [
+        "Slice(FindEnd(X, ' ', 0), $)",
+        "Uppercase(Slice(FindEnd(X, ' ', 0), $))",
+        "Concat(Slice(FindEnd(X, ' ', 0), $), '!')",
+        "Concat(Slice(0, Find(X, ' ', 0)), Slice(FindEnd(X, ' ', 0), $))",
+    ]
+    .map(|p| parse_sketch(p).unwrap());
+
+    let library = Library::learn(&programs, 1);
+    assert_eq!(library.abstractions().len(), 1);
+    let rest = library.abstractions()[0].fun;
+    assert_eq!(library.to_string(), format!("{} := Slice(FindEnd(X, ' ', 0), $)\n", rest.name()));
+
+    let rewritten = library.rewrite(&programs[2]);
+    assert_eq!(rewritten.size(), 3);
+    assert_eq!(rewritten.eval(&s("John Smith")), s("Smith!"));
+
+    // loading it again defines the same function
+    let loaded = library.to_string().parse::<Library>().unwrap();
+    assert_eq!(loaded.funs().collect::<Vec<_>>(), vec![rest]);
+    assert!("Concat := Slice(0, 1)".parse::<Library>().is_err());
+
+    let examples = [(s("John Smith"), s("Smith!")), (s("Ada Lovelace"), s("Lovelace!"))];
+    let config = SynthConfig::default().library(&loaded);
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    println!("{prog}");
+    assert!(prog.to_string().contains(&rest.name()));
+    assert_eq!(prog.eval(&s("Grace Hopper")), s("Hopper!"));
+}
+
+#[test]
+fn test_custom_fun() {
+    use crate::vsa::{custom, Fun, Type, AST};
+
+    let s = |s: &str| StringConst(s.to_string());
+    let rev = |s: &Lit| match s {
+        StringConst(s) => StringConst(s.chars().rev().collect()),
+        _ => panic!(),
+    };
+    let reverse = custom::register(
+        custom::CustomFun::new("reverse", vec![Type::String], Type::String, move |args| rev(&args[0]))
+            .witness(move |_, out| vec![vec![rev(out)]]),
+    )
+    .unwrap();
+    assert_eq!(custom::lookup("reverse"), Some(reverse));
+
+    // names are taken once, and builtins are always taken
+    let same = |name: &str| {
+        custom::CustomFun::new(name, vec![Type::String], Type::String, |args| args[0].clone())
+    };
+    assert!(custom::register(same("reverse")).is_err());
+    assert!(custom::register(same("Concat")).is_err());
+    let call = AST::App { fun: reverse, args: vec![AST::Lit(s("abc"))] };
+    assert_eq!(call.eval(&s("")), s("cba"));
+
+    // a witness that returns the wrong number of arguments is ignored
+    let twice = same("twice").witness(|_, out| vec![vec![out.clone(), out.clone()]]);
+    let Ok(Fun::Custom(twice)) = custom::register(twice) else {
+        panic!()
+    };
+    assert!(custom::witness(twice, &s("a"), &s("a")).is_empty());
+
+    // bottom up only
+    let examples = [(s("abc"), s("cba")), (s("hello"), s("olleh"))];
+    let config = SynthConfig::default().max_size(3).enable(reverse);
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    assert!(prog.to_string().starts_with("reverse("));
+    assert_eq!(prog.eval(&s("stressed")), s("desserts"));
+
+    // needs the witness inside a concat
+    let examples = [(s("ab"), s("ab-ba")), (s("xyz"), s("xyz-zyx"))];
+    let prog = crate::enumerative::duet(&examples, &config).unwrap();
+    println!("{prog}");
+    assert_eq!(prog.eval(&s("pq")), s("pq-qp"));
+}
+
+#[cfg(test)]
+mod toy_dsl {
+    use crate::bank::Bank;
+    use crate::enumerative::{Dsl, DslAST, DuetSession, Goal, Witness};
+    use crate::vsa::{InputLit, Language, AST, VSA};
+    use std::rc::Rc;
+
+    #[derive(Clone, PartialEq, Eq, Hash, Debug)]
+    pub enum Num {
+        Int(i64),
+        Input,
+    }
+
+    impl InputLit for Num {
+        fn is_input(&self) -> bool {
+            self == &Num::Input
+        }
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+    pub enum Op {
+        Add,
+        Mul,
+        Neg,
+    }
+
+    impl Language<Num> for Op {
+        type Hole = std::convert::Infallible;
+
+        fn eval(&self, args: &[Num], _input: &Num) -> Num {
+            match (self, args) {
+                (Op::Add, [Num::Int(a), Num::Int(b)]) => Num::Int(a + b),
+                (Op::Mul, [Num::Int(a), Num::Int(b)]) => Num::Int(a * b),
+                (Op::Neg, [Num::Int(a)]) => Num::Int(-a),
+                _ => panic!(),
+            }
+        }
+    }
+
+    impl Witness<Arith> for Op {
+        fn witness(&self, inp: &Num, out: &Num, _dsl: &Arith, _bank: &Bank<DslAST<Arith>>) -> Vec<Vec<Goal<Arith>>> {
+            match (self, inp, out) {
+                (Op::Add, Num::Int(x), Num::Int(y)) => {
+                    let rhs = VSA::singleton(AST::Lit(Num::Int(y - x)));
+                    vec![vec![Goal::Learn(Num::Input), Goal::Fixed(Rc::new(rhs))]]
+                }
+                // learning y needs -y, which needs y again
+                (Op::Neg, Num::Int(_), Num::Int(y)) => vec![vec![Goal::Learn(Num::Int(-y))]],
+                _ => vec![],
+            }
+        }
+    }
+
+    pub struct Arith;
+
+    impl Dsl for Arith {
+        type L = Num;
+        type F = Op;
+        type Partial = std::convert::Infallible;
+
+        fn seeds_changed(&self, _examples: &[(Num, Num)]) -> bool {
+            false
+        }
+
+        fn seeds(&mut self, _examples: &[(Num, Num)]) -> Vec<Num> {
+            vec![Num::Input, Num::Int(2)]
+        }
+
+        fn funs(&self) -> Vec<Op> {
+            vec![Op::Add, Op::Mul, Op::Neg]
+        }
+
+        fn lit_witness(&self, _inp: &Num, out: &Num) -> Vec<DslAST<Arith>> {
+            match out {
+                Num::Int(_) => vec![AST::Lit(out.clone())],
+                Num::Input => vec![],
+            }
+        }
+
+        fn enumerate<'a>(&'a self, size: usize, bank: &'a Bank<DslAST<Arith>>) -> Box<dyn Iterator<Item = DslAST<Arith>> + 'a> {
+            Box::new((size == 3).then(|| bank.size(1).iter()).into_iter().flatten().flat_map(move |lhs| {
+                bank.size(1).iter().map(move |rhs| AST::App {
+                    fun: Op::Mul,
+                    args: vec![lhs.clone(), rhs.clone()],
+                })
+            }))
+        }
+
+        fn rank(&self, ast: &DslAST<Arith>) -> usize {
+            ast.size()
+        }
+
+        fn satisfies(&self, partial: &Self::Partial, _out: &Num) -> bool {
+            match *partial {}
+        }
+    }
+
+    #[test]
+    fn test_generic_engine() {
+        let mut session = DuetSession::from_dsl(Arith, &[(Num::Int(1), Num::Int(4)), (Num::Int(5), Num::Int(8))]);
+        let prog = session.synthesize().unwrap();
+        assert_eq!(prog.eval(&Num::Int(10)), Num::Int(13));
+
+        let mut session = DuetSession::from_dsl(Arith, &[(Num::Int(3), Num::Int(6)), (Num::Int(5), Num::Int(10))]);
+        let prog = session.synthesize().unwrap();
+        assert_eq!(prog.eval(&Num::Int(10)), Num::Int(20));
+    }
+
+    #[test]
+    fn test_cyclic_witness() {
+        let mut session = DuetSession::from_dsl(Arith, &[(Num::Int(1), Num::Int(-4)), (Num::Int(2), Num::Int(-5))]);
+        let prog = session.synthesize().unwrap();
+        assert_eq!(prog.eval(&Num::Int(10)), Num::Int(-13));
+
+        // the cycle is unrolled, so Neg(Neg(..)) is in there too
+        let vsa = session.version_space().unwrap();
+        assert!(vsa.contains(&AST::App {
+            fun: Op::Neg,
+            args: vec![AST::App {
+                fun: Op::Neg,
+                args: vec![prog.clone()],
+            }],
+        }));
+    }
+}
@@ -1,7 +1,24 @@
 use itertools::Itertools;
 use std::{collections::HashMap, collections::HashSet, fmt::Display, rc::Rc};
 
+mod algebra;
+pub mod arena;
+mod count;
+pub mod cursor;
+pub mod custom;
+mod export;
+
+pub use algebra::Symbol;
+pub use count::Histogram;
+pub(crate) use export::json_string;
+
+use arena::Arena;
+
 pub trait Language<L> {
+    // What a hole in a sketch says about what can go in it, see `AST::Hole`.
+    // A DSL without sketches can use `std::convert::Infallible`.
+    type Hole: Clone + Eq + std::hash::Hash + std::fmt::Debug;
+
     fn eval(&self, args: &[L], input: &L) -> L;
 }
 
@@ -73,71 +90,54 @@ where
         // }
     }
 
-    fn contains(&self, program: &AST<L, F>) -> bool {
+    pub fn contains(&self, program: &AST<L, F>) -> bool {
         match self {
             VSA::Leaf(s) => s.contains(program),
             VSA::Union(vss) => vss.iter().any(|vs| vs.contains(program)),
             VSA::Join { op, children } => match program {
-                AST::App { fun, args } if fun == op => args
+                AST::App { fun, args } if fun == op && args.len() == children.len() => args
                     .iter()
-                    .all(|arg| children.iter().any(|vss| vss.contains(arg))),
+                    .zip(children)
+                    .all(|(arg, vss)| vss.contains(arg)),
                 _ => false,
             },
         }
     }
 
-    // https://dl.acm.org/doi/pdf/10.1145/2858965.2814310
-    // page 10
+    // Goes through an `Arena`, see `Arena::intersect`. Callers that intersect
+    // more than once should keep the arena around instead.
     pub fn intersect(&self, other: &VSA<L, F>) -> VSA<L, F> {
-        match (self, other) {
-            (vsa, VSA::Union(union)) | (VSA::Union(union), vsa) => VSA::Union(
-                union
-                    .iter()
-                    .map(|n1| Rc::new(n1.clone().intersect(vsa)))
-                    .collect(),
-            ),
+        let mut arena = Arena::new();
+        let (l, r) = (arena.import(self), arena.import(other));
+        let res = arena.intersect(l, r);
+        arena.export(res).as_ref().clone()
+    }
 
-            #[rustfmt::skip]
-            (VSA::Join { op: l_op, .. }, VSA::Join { op: r_op, .. })
-                if l_op != r_op => VSA::empty(),
-
-            #[rustfmt::skip]
-            (VSA::Join { op, children: l_children }, VSA::Join { op: _, children: r_children })
-                => VSA::Join {
-                    op: *op,
-                    children: l_children.iter().zip(r_children).map(|(l, r)| Rc::new(l.intersect(r))).collect()
-                },
-
-            #[rustfmt::skip]
-            (VSA::Join { op, children }, VSA::Leaf(s)) | (VSA::Leaf(s), VSA::Join { op, children })
-                => VSA::Leaf(s.iter().filter(|pj| {
-                    match pj.as_ref() {
-                        AST::App { fun, args } if fun == op =>
-                            args.iter().zip(children).all(|(arg, vsa)| vsa.contains(arg)),
-                        _ => false
-                    }
-                }).cloned().collect()),
-
-            (VSA::Leaf(l_set), VSA::Leaf(r_set)) => {
-                VSA::Leaf(l_set.intersection(r_set).cloned().collect())
-            }
-        }
+    // Programs of `self` that aren't in `other`, see `Arena::difference`
+    pub fn difference(&self, other: &VSA<L, F>) -> VSA<L, F> {
+        let mut arena = Arena::new();
+        let (l, r) = (arena.import(self), arena.import(other));
+        let res = arena.difference(l, r);
+        arena.export(res).as_ref().clone()
     }
 
-    fn group_by(map: HashMap<L, Rc<VSA<L, F>>>) -> HashMap<L, Rc<VSA<L, F>>> {
-        // TODO: do it in O(n)
-        map.iter()
-            .map(|(o1, _)| {
-                (
-                    o1.clone(),
-                    Rc::new(VSA::Union(
-                        map.iter()
-                            .filter(|(o2, _)| &o1 == o2)
-                            .map(|(_, v)| v.clone())
-                            .collect(),
-                    )),
-                )
-            })
+    // Rebuilds the VSA without dead branches, nested unions or duplicate
+    // nodes, see `Arena`
+    pub fn minimize(&self) -> Rc<VSA<L, F>> {
+        let mut arena = Arena::new();
+        let id = arena.import(self);
+        arena.export(id)
+    }
+
+    fn group_by(pairs: impl IntoIterator<Item = (L, Rc<VSA<L, F>>)>) -> HashMap<L, Rc<VSA<L, F>>> {
+        let mut groups: HashMap<L, Vec<Rc<VSA<L, F>>>> = HashMap::new();
+        for (out, vsa) in pairs {
+            groups.entry(out).or_default().push(vsa);
+        }
+
+        groups
+            .into_iter()
+            .map(|(out, vsas)| (out, Rc::new(VSA::Union(vsas))))
             .collect()
     }
 
@@ -190,41 +190,44 @@ where
 
     fn cluster(vsa: Rc<VSA<L, F>>, input: &L) -> HashMap<L, Rc<VSA<L, F>>> {
         match vsa.as_ref() {
-            VSA::Leaf(s) => VSA::group_by(
-                s.iter()
-                    .map(|p| {
-                        (
-                            p.eval(input),
-                            Rc::new(VSA::Leaf(std::iter::once(p.clone()).collect())),
-                        )
-                    })
-                    .collect(),
-            ),
-            VSA::Union(s) => VSA::group_by(
-                // the union of all the clusters
-                s.iter()
-                    .map(|vsa| VSA::cluster(vsa.clone(), input))
-                    .reduce(|a, b| a.into_iter().chain(b.into_iter()).collect())
-                    .unwrap(),
-            ),
+            VSA::Leaf(s) => VSA::group_by(s.iter().map(|p| {
+                (
+                    p.eval(input),
+                    Rc::new(VSA::Leaf(std::iter::once(p.clone()).collect())),
+                )
+            })),
+            // the union of all the clusters
+            VSA::Union(s) => VSA::group_by(s.iter().flat_map(|vsa| VSA::cluster(vsa.clone(), input))),
             VSA::Join { op, children } => {
                 let ns = children.iter().map(|vsa| VSA::cluster(vsa.clone(), input));
-                VSA::group_by(
-                    ns.map(|m| {
-                        let ast = AST::App {
-                            fun: *op,
-                            args: m.keys().map(|l| AST::Lit(l.clone())).collect(),
-                        };
-                        let res = ast.eval(input);
-                        (res, vsa.clone())
-                    })
-                    .collect(),
-                )
+                VSA::group_by(ns.map(|m| {
+                    let ast = AST::App {
+                        fun: *op,
+                        args: m.keys().map(|l| AST::Lit(l.clone())).collect(),
+                    };
+                    let res = ast.eval(input);
+                    (res, vsa.clone())
+                }))
             }
         }
     }
 }
 
+impl<L, F> VSA<L, F>
+where
+    L: Clone + Eq + std::hash::Hash + std::fmt::Debug + InputLit + Cost,
+    F: Language<L> + Eq + Copy + std::hash::Hash + std::fmt::Debug + Cost,
+{
+    // `minimize`, and then drop what can only cost more than `max_cost`, see
+    // `Arena::prune`
+    pub fn prune(&self, max_cost: usize) -> Rc<VSA<L, F>> {
+        let mut arena = Arena::new();
+        let id = arena.import(self);
+        let id = arena.prune(id, max_cost);
+        arena.export(id)
+    }
+}
+
 pub trait Cost {
     fn cost(&self) -> usize;
 }
@@ -241,17 +244,88 @@ pub enum Fun {
     Uppercase,
     ConcatMap,
     Equal,
+    // see `custom::register`
+    Custom(usize),
+}
+
+impl Fun {
+    pub const ALL: [Fun; 10] = [
+        Fun::Concat,
+        Fun::Find,
+        Fun::FindEnd,
+        Fun::Slice,
+        Fun::LocAdd,
+        Fun::LocSub,
+        Fun::Lowercase,
+        Fun::Uppercase,
+        Fun::ConcatMap,
+        Fun::Equal,
+    ];
+
+    // the builtins and everything registered so far
+    pub fn all() -> Vec<Fun> {
+        Fun::ALL.into_iter().chain(custom::registered()).collect()
+    }
+
+    pub fn ret(&self) -> Type {
+        match self {
+            Fun::Concat | Fun::Slice | Fun::Lowercase | Fun::Uppercase | Fun::ConcatMap => {
+                Type::String
+            }
+            Fun::Find | Fun::FindEnd | Fun::LocAdd | Fun::LocSub => Type::Loc,
+            Fun::Equal => Type::Bool,
+            Fun::Custom(id) => custom::ret(*id),
+        }
+    }
+
+    // Equal also works on strings, but it's only ever used on locations
+    pub fn args(&self) -> Vec<Type> {
+        use Type::*;
+        match self {
+            Fun::Lowercase | Fun::Uppercase => vec![String],
+            Fun::Concat | Fun::ConcatMap => vec![String, String],
+            Fun::Slice | Fun::LocAdd | Fun::LocSub | Fun::Equal => vec![Loc, Loc],
+            Fun::Find | Fun::FindEnd => vec![String, String, Loc],
+            Fun::Custom(id) => custom::args(*id),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Fun::Custom(id) => custom::name(*id),
+            _ => format!("{self:?}"),
+        }
+    }
+}
+
+impl std::str::FromStr for Fun {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Fun::all()
+            .into_iter()
+            .find(|fun| fun.name() == s)
+            .ok_or_else(|| format!("unknown function {s}"))
+    }
 }
 
 impl Cost for Fun {
     fn cost(&self) -> usize {
         match self {
             Fun::Concat => 2,
+            Fun::Custom(id) => custom::cost(*id),
             _ => 1,
         }
     }
 }
 
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum Type {
+    String,
+    Loc,
+    Bool,
+}
+
 pub trait InputLit {
     fn is_input(&self) -> bool;
 }
@@ -271,6 +345,16 @@ impl InputLit for Lit {
     }
 }
 
+impl Lit {
+    pub fn ty(&self) -> Type {
+        match self {
+            Lit::StringConst(_) | Lit::Input => Type::String,
+            Lit::LocConst(_) | Lit::LocEnd => Type::Loc,
+            Lit::BoolConst(_) => Type::Bool,
+        }
+    }
+}
+
 impl Cost for Lit {
     fn cost(&self) -> usize {
         match self {
@@ -288,9 +372,13 @@ where
 {
     App { fun: F, args: Vec<AST<L, F>> },
     Lit(L),
+    // only in sketches, see `enumerative::complete_sketch`
+    Hole(F::Hole),
 }
 
 impl Language<Lit> for Fun {
+    type Hole = Type;
+
     fn eval(&self, args: &[Lit], input: &Lit) -> Lit {
         match self {
             Fun::Concat => match args {
@@ -380,6 +468,7 @@ impl Language<Lit> for Fun {
                 [Lit::StringConst(s)] => Lit::StringConst(s.to_uppercase()),
                 _ => panic!(),
             },
+            Fun::Custom(id) => custom::eval(*id, args, input),
         }
     }
 }
@@ -397,15 +486,51 @@ where
                 let evaled = args.iter().map(|ast| ast.eval(inp)).collect::<Vec<_>>();
                 fun.eval(&evaled, inp)
             }
+            AST::Hole(_) => panic!("can't evaluate a hole"),
         }
     }
 
     pub fn size(&self) -> usize {
         match self {
-            AST::Lit(_) => 1,
+            AST::Lit(_) | AST::Hole(_) => 1,
             AST::App { args, .. } => 1 + args.iter().map(AST::size).sum::<usize>(),
         }
     }
+
+    // the types of the holes, left to right
+    pub fn holes(&self) -> Vec<F::Hole> {
+        match self {
+            AST::Lit(_) => Vec::new(),
+            AST::Hole(hole) => vec![hole.clone()],
+            AST::App { args, .. } => args.iter().flat_map(AST::holes).collect(),
+        }
+    }
+
+    pub fn has_holes(&self) -> bool {
+        match self {
+            AST::Lit(_) => false,
+            AST::Hole(_) => true,
+            AST::App { args, .. } => args.iter().any(AST::has_holes),
+        }
+    }
+
+    // whether `self` is `sketch` with its holes filled in
+    pub fn completes(&self, sketch: &AST<L, F>) -> bool
+    where
+        L: PartialEq,
+        F: PartialEq,
+    {
+        match (self, sketch) {
+            (_, AST::Hole(_)) => true,
+            (AST::App { fun, args }, AST::App { fun: s_fun, args: s_args }) => {
+                fun == s_fun
+                    && args.len() == s_args.len()
+                    && args.iter().zip(s_args).all(|(arg, s_arg)| arg.completes(s_arg))
+            }
+            (AST::Lit(l), AST::Lit(s_l)) => l == s_l,
+            _ => false,
+        }
+    }
 }
 
 impl<L, F> Cost for AST<L, F>
@@ -416,6 +541,7 @@ where
     fn cost(&self) -> usize {
         match self {
             AST::Lit(l) => l.cost(),
+            AST::Hole(_) => 1,
             AST::App { fun, args } => fun.cost() + args.iter().map(AST::size).sum::<usize>(),
         }
     }
@@ -517,11 +643,19 @@ impl Display for AST<Lit, Fun> {
                 let b = args[1].clone();
                 write!(f, "({a} == {b})")
             }
+            AST::App {
+                fun: Fun::Custom(id),
+                args,
+            } => {
+                let args = args.iter().map(|arg| arg.to_string()).join(", ");
+                write!(f, "{}({args})", custom::name(*id))
+            }
             AST::Lit(Lit::StringConst(s)) => write!(f, "'{}'", s),
             AST::Lit(Lit::LocConst(n)) => write!(f, "{}", n),
             AST::Lit(Lit::BoolConst(b)) => write!(f, "{}", b),
             AST::Lit(Lit::LocEnd) => write!(f, "$"),
             AST::Lit(Lit::Input) => write!(f, "X"),
+            AST::Hole(_) => write!(f, "?"),
         }
     }
 }
@@ -542,6 +676,7 @@ where
                 write!(f, "({:?} [ {}])", fun, args)
             }
             AST::Lit(l) => write!(f, "{:?}", l),
+            AST::Hole(_) => write!(f, "?"),
         }
     }
 }
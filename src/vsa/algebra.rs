@@ -0,0 +1,103 @@
+use std::{collections::HashMap, hash::Hash, rc::Rc};
+
+use super::{InputLit, Language, AST, VSA};
+
+// Everything a program can be made out of, for `VSA::filter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symbol<'a, L, F> {
+    Lit(&'a L),
+    Fun(F),
+}
+
+impl<L, F> AST<L, F>
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug,
+{
+    pub fn all_symbols(&self, keep: impl Fn(Symbol<L, F>) -> bool + Copy) -> bool {
+        match self {
+            AST::Lit(l) => keep(Symbol::Lit(l)),
+            AST::Hole(_) => true,
+            AST::App { fun, args } => {
+                keep(Symbol::Fun(*fun)) && args.iter().all(|arg| arg.all_symbols(keep))
+            }
+        }
+    }
+}
+
+impl<L, F> VSA<L, F>
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug,
+{
+    // Keeps the programs where every literal and function passes `keep`,
+    // e.g. `|s| !matches!(s, Symbol::Lit(Lit::StringConst(_)))` for programs
+    // without string constants
+    pub fn filter(&self, keep: impl Fn(Symbol<L, F>) -> bool + Copy) -> Rc<VSA<L, F>> {
+        self.filter_memo(keep, &mut HashMap::new()).minimize()
+    }
+
+    fn filter_memo(
+        &self,
+        keep: impl Fn(Symbol<L, F>) -> bool + Copy,
+        memo: &mut HashMap<*const VSA<L, F>, Rc<VSA<L, F>>>,
+    ) -> Rc<VSA<L, F>> {
+        if let Some(vsa) = memo.get(&(self as *const _)) {
+            return vsa.clone();
+        }
+
+        let vsa = Rc::new(match self {
+            VSA::Leaf(asts) => VSA::Leaf(
+                asts.iter()
+                    .filter(|ast| ast.all_symbols(keep))
+                    .cloned()
+                    .collect(),
+            ),
+            VSA::Union(children) => VSA::Union(
+                children
+                    .iter()
+                    .map(|c| c.filter_memo(keep, memo))
+                    .collect(),
+            ),
+            VSA::Join { op, .. } if !keep(Symbol::Fun(*op)) => VSA::empty(),
+            VSA::Join { op, children } => VSA::Join {
+                op: *op,
+                children: children
+                    .iter()
+                    .map(|c| c.filter_memo(keep, memo))
+                    .collect(),
+            },
+        });
+
+        memo.insert(self as *const _, vsa.clone());
+        vsa
+    }
+
+    // Argument `i` of every program that has `op` at the top
+    pub fn project(&self, op: F, i: usize) -> Rc<VSA<L, F>> {
+        self.project_unminimized(op, i).minimize()
+    }
+
+    fn project_unminimized(&self, op: F, i: usize) -> Rc<VSA<L, F>> {
+        match self {
+            VSA::Leaf(asts) => Rc::new(VSA::Leaf(
+                asts.iter()
+                    .filter_map(|ast| match ast.as_ref() {
+                        AST::App { fun, args } if *fun == op => args.get(i).cloned().map(Rc::new),
+                        _ => None,
+                    })
+                    .collect(),
+            )),
+            VSA::Union(children) => Rc::new(VSA::Union(
+                children
+                    .iter()
+                    .map(|c| c.project_unminimized(op, i))
+                    .collect(),
+            )),
+            VSA::Join { op: fun, children } if *fun == op && i < children.len() => {
+                children[i].clone()
+            }
+            VSA::Join { .. } => Rc::new(VSA::empty()),
+        }
+    }
+}
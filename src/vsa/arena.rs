@@ -0,0 +1,510 @@
+use std::{collections::HashMap, hash::Hash, rc::Rc};
+
+use itertools::Itertools;
+
+use super::{Cost, InputLit, Language, AST, VSA};
+
+pub type VsaId = usize;
+
+// A node whose children are ids into the arena it lives in. Nodes are only
+// ever built through the constructors on `Arena`, which keep them canonical:
+// leaves are sorted, unions are flat, sorted, deduplicated and have at least
+// two children, and nothing points at the empty node except through a leaf.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Node<F> {
+    // ids into `Arena::asts`
+    Leaf(Vec<usize>),
+    Union(Vec<VsaId>),
+    Join { op: F, children: Vec<VsaId> },
+}
+
+// Hash-consed storage for VSAs. Structurally identical nodes share one id, so
+// intersecting two VSAs only has to look at each pair of nodes once.
+pub struct Arena<L, F>
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug,
+{
+    asts: Vec<Rc<AST<L, F>>>,
+    ast_ids: HashMap<Rc<AST<L, F>>, usize>,
+    nodes: Vec<Node<F>>,
+    node_ids: HashMap<Node<F>, VsaId>,
+    intersections: HashMap<(VsaId, VsaId), VsaId>,
+    differences: HashMap<(VsaId, VsaId), VsaId>,
+}
+
+impl<L, F> Default for Arena<L, F>
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug,
+{
+    fn default() -> Self {
+        Arena::new()
+    }
+}
+
+impl<L, F> Arena<L, F>
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug,
+{
+    pub const EMPTY: VsaId = 0;
+
+    pub fn new() -> Self {
+        let mut arena = Arena {
+            asts: Vec::new(),
+            ast_ids: HashMap::new(),
+            nodes: Vec::new(),
+            node_ids: HashMap::new(),
+            intersections: HashMap::new(),
+            differences: HashMap::new(),
+        };
+        arena.insert(Node::Leaf(Vec::new()));
+        arena
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.len() == 1
+    }
+
+    pub fn node(&self, id: VsaId) -> &Node<F> {
+        &self.nodes[id]
+    }
+
+    pub fn ast(&self, i: usize) -> &Rc<AST<L, F>> {
+        &self.asts[i]
+    }
+
+    fn insert(&mut self, node: Node<F>) -> VsaId {
+        if let Some(id) = self.node_ids.get(&node) {
+            return *id;
+        }
+
+        self.nodes.push(node.clone());
+        self.node_ids.insert(node, self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    fn intern(&mut self, ast: Rc<AST<L, F>>) -> usize {
+        if let Some(i) = self.ast_ids.get(&ast) {
+            return *i;
+        }
+
+        self.asts.push(ast.clone());
+        self.ast_ids.insert(ast, self.asts.len() - 1);
+        self.asts.len() - 1
+    }
+
+    pub fn leaf(&mut self, asts: impl IntoIterator<Item = Rc<AST<L, F>>>) -> VsaId {
+        let ids = asts
+            .into_iter()
+            .map(|ast| self.intern(ast))
+            .sorted()
+            .dedup()
+            .collect::<Vec<_>>();
+        self.insert(Node::Leaf(ids))
+    }
+
+    // nested unions are flattened and all the leaves are merged into one
+    pub fn union(&mut self, children: impl IntoIterator<Item = VsaId>) -> VsaId {
+        let mut leaf = Vec::new();
+        let mut rest = Vec::new();
+        let mut stack = children.into_iter().collect::<Vec<_>>();
+
+        while let Some(id) = stack.pop() {
+            match &self.nodes[id] {
+                Node::Leaf(asts) => leaf.extend(asts.iter().copied()),
+                Node::Union(children) => stack.extend(children.iter().copied()),
+                Node::Join { .. } => rest.push(id),
+            }
+        }
+
+        if !leaf.is_empty() {
+            leaf.sort_unstable();
+            leaf.dedup();
+            rest.push(self.insert(Node::Leaf(leaf)));
+        }
+
+        rest.sort_unstable();
+        rest.dedup();
+        match rest.len() {
+            0 => Self::EMPTY,
+            1 => rest[0],
+            _ => self.insert(Node::Union(rest)),
+        }
+    }
+
+    // a join with an empty argument can't build anything
+    pub fn join(&mut self, op: F, children: Vec<VsaId>) -> VsaId {
+        if children.contains(&Self::EMPTY) {
+            Self::EMPTY
+        } else {
+            self.insert(Node::Join { op, children })
+        }
+    }
+
+    // Shared sub-VSAs are only imported once
+    pub fn import(&mut self, vsa: &VSA<L, F>) -> VsaId {
+        self.import_memo(vsa, &mut HashMap::new())
+    }
+
+    fn import_memo(&mut self, vsa: &VSA<L, F>, memo: &mut HashMap<*const VSA<L, F>, VsaId>) -> VsaId {
+        if let Some(id) = memo.get(&(vsa as *const _)) {
+            return *id;
+        }
+
+        let id = match vsa {
+            VSA::Leaf(asts) => self.leaf(asts.iter().cloned()),
+            VSA::Union(children) => {
+                let children = children
+                    .iter()
+                    .map(|c| self.import_memo(c, memo))
+                    .collect::<Vec<_>>();
+                self.union(children)
+            }
+            VSA::Join { op, children } => {
+                let children = children
+                    .iter()
+                    .map(|c| self.import_memo(c, memo))
+                    .collect::<Vec<_>>();
+                self.join(*op, children)
+            }
+        };
+
+        memo.insert(vsa as *const _, id);
+        id
+    }
+
+    // the result shares an `Rc` wherever the arena shares an id
+    pub fn export(&self, id: VsaId) -> Rc<VSA<L, F>> {
+        self.export_memo(id, &mut HashMap::new())
+    }
+
+    fn export_memo(&self, id: VsaId, memo: &mut HashMap<VsaId, Rc<VSA<L, F>>>) -> Rc<VSA<L, F>> {
+        if let Some(vsa) = memo.get(&id) {
+            return vsa.clone();
+        }
+
+        let vsa = Rc::new(match &self.nodes[id] {
+            Node::Leaf(asts) => VSA::Leaf(asts.iter().map(|i| self.asts[*i].clone()).collect()),
+            Node::Union(children) => {
+                VSA::Union(children.iter().map(|c| self.export_memo(*c, memo)).collect())
+            }
+            Node::Join { op, children } => VSA::Join {
+                op: *op,
+                children: children.iter().map(|c| self.export_memo(*c, memo)).collect(),
+            },
+        });
+
+        memo.insert(id, vsa.clone());
+        vsa
+    }
+
+    // https://dl.acm.org/doi/pdf/10.1145/2858965.2814310
+    // page 10, memoized on pairs of ids
+    pub fn intersect(&mut self, a: VsaId, b: VsaId) -> VsaId {
+        if a == b {
+            return a;
+        }
+        if a == Self::EMPTY || b == Self::EMPTY {
+            return Self::EMPTY;
+        }
+
+        let key = (a.min(b), a.max(b));
+        if let Some(id) = self.intersections.get(&key) {
+            return *id;
+        }
+
+        let id = match (self.nodes[a].clone(), self.nodes[b].clone()) {
+            (Node::Union(union), _) => {
+                let children = union.iter().map(|c| self.intersect(*c, b)).collect::<Vec<_>>();
+                self.union(children)
+            }
+            (_, Node::Union(union)) => {
+                let children = union.iter().map(|c| self.intersect(a, *c)).collect::<Vec<_>>();
+                self.union(children)
+            }
+
+            (
+                Node::Join { op: l_op, children: l_children },
+                Node::Join { op: r_op, children: r_children },
+            ) => {
+                if l_op != r_op || l_children.len() != r_children.len() {
+                    Self::EMPTY
+                } else {
+                    let children = l_children
+                        .iter()
+                        .zip(r_children.iter())
+                        .map(|(l, r)| self.intersect(*l, *r))
+                        .collect();
+                    self.join(l_op, children)
+                }
+            }
+
+            (Node::Join { .. }, Node::Leaf(asts)) => {
+                let kept = asts
+                    .into_iter()
+                    .filter(|i| self.contains(a, &self.asts[*i]))
+                    .collect();
+                self.insert(Node::Leaf(kept))
+            }
+            (Node::Leaf(asts), Node::Join { .. }) => {
+                let kept = asts
+                    .into_iter()
+                    .filter(|i| self.contains(b, &self.asts[*i]))
+                    .collect();
+                self.insert(Node::Leaf(kept))
+            }
+
+            (Node::Leaf(l), Node::Leaf(r)) => {
+                let kept = l.into_iter().filter(|i| r.binary_search(i).is_ok()).collect();
+                self.insert(Node::Leaf(kept))
+            }
+        };
+
+        self.intersections.insert(key, id);
+        id
+    }
+
+    // The programs of `a` that aren't in `b`. Joins are split up one argument
+    // at a time, i.e. (c1 x c2) - (d1 x d2) = (c1 - d1) x c2 + c1 x (c2 - d2),
+    // so this is meant for taking out a handful of programs, not for
+    // subtracting one big VSA from another.
+    pub fn difference(&mut self, a: VsaId, b: VsaId) -> VsaId {
+        if a == b || a == Self::EMPTY {
+            return Self::EMPTY;
+        }
+        if b == Self::EMPTY {
+            return a;
+        }
+
+        if let Some(id) = self.differences.get(&(a, b)) {
+            return *id;
+        }
+
+        let id = match (self.nodes[a].clone(), self.nodes[b].clone()) {
+            (Node::Leaf(asts), _) => {
+                let kept = asts
+                    .into_iter()
+                    .filter(|i| !self.contains(b, &self.asts[*i]))
+                    .collect();
+                self.insert(Node::Leaf(kept))
+            }
+            (Node::Union(union), _) => {
+                let children = union
+                    .iter()
+                    .map(|c| self.difference(*c, b))
+                    .collect::<Vec<_>>();
+                self.union(children)
+            }
+            (_, Node::Union(union)) => union.iter().fold(a, |acc, c| self.difference(acc, *c)),
+
+            (
+                Node::Join { op: l_op, children: l_children },
+                Node::Join { op: r_op, children: r_children },
+            ) => {
+                if l_op != r_op || l_children.len() != r_children.len() {
+                    a
+                } else {
+                    self.difference_by_argument(l_op, &l_children, &r_children)
+                }
+            }
+
+            // one program at a time, since `a` turns into a union as soon as
+            // the first one is taken out
+            (Node::Join { .. }, Node::Leaf(asts)) if asts.len() > 1 => asts.into_iter().fold(a, |acc, i| {
+                let single = self.insert(Node::Leaf(vec![i]));
+                self.difference(acc, single)
+            }),
+            (Node::Join { op, children }, Node::Leaf(asts)) => {
+                let program = self.asts[asts[0]].clone();
+                match program.as_ref() {
+                    AST::App { fun, args } if *fun == op && self.contains(a, &program) => {
+                        let args = args
+                            .iter()
+                            .map(|arg| self.leaf(std::iter::once(Rc::new(arg.clone()))))
+                            .collect::<Vec<_>>();
+                        self.difference_by_argument(op, &children, &args)
+                    }
+                    _ => a,
+                }
+            }
+        };
+
+        self.differences.insert((a, b), id);
+        id
+    }
+
+    fn difference_by_argument(&mut self, op: F, l_children: &[VsaId], r_children: &[VsaId]) -> VsaId {
+        let joins = (0..l_children.len())
+            .map(|i| {
+                let mut children = l_children.to_vec();
+                children[i] = self.difference(l_children[i], r_children[i]);
+                self.join(op, children)
+            })
+            .collect::<Vec<_>>();
+        self.union(joins)
+    }
+
+    pub fn contains(&self, id: VsaId, program: &AST<L, F>) -> bool {
+        match &self.nodes[id] {
+            Node::Leaf(asts) => self
+                .ast_ids
+                .get(program)
+                .is_some_and(|i| asts.binary_search(i).is_ok()),
+            Node::Union(children) => children.iter().any(|c| self.contains(*c, program)),
+            Node::Join { op, children } => match program {
+                AST::App { fun, args } if fun == op && args.len() == children.len() => args
+                    .iter()
+                    .zip(children)
+                    .all(|(arg, c)| self.contains(*c, arg)),
+                _ => false,
+            },
+        }
+    }
+
+    // Same choices as `VSA::pick_best`, but every node is only visited once
+    pub fn pick_best(&self, id: VsaId, rank: impl Fn(&AST<L, F>) -> usize + Copy) -> Option<AST<L, F>> {
+        self.pick_best_memo(id, rank, &mut HashMap::new())
+    }
+
+    fn pick_best_memo(
+        &self,
+        id: VsaId,
+        rank: impl Fn(&AST<L, F>) -> usize + Copy,
+        memo: &mut HashMap<VsaId, Option<AST<L, F>>>,
+    ) -> Option<AST<L, F>> {
+        if let Some(best) = memo.get(&id) {
+            return best.clone();
+        }
+
+        let best = match &self.nodes[id] {
+            Node::Leaf(asts) => asts
+                .iter()
+                .map(|i| self.asts[*i].as_ref())
+                .min_by_key(|ast| rank(ast))
+                .cloned(),
+            Node::Union(children) => children
+                .iter()
+                .filter_map(|c| self.pick_best_memo(*c, rank, memo))
+                .min_by_key(rank),
+            Node::Join { op, children } => children
+                .iter()
+                .map(|c| self.pick_best_memo(*c, rank, memo))
+                .collect::<Option<Vec<_>>>()
+                .map(|args| AST::App { fun: *op, args }),
+        };
+
+        memo.insert(id, best.clone());
+        best
+    }
+}
+
+impl<L, F> Arena<L, F>
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit + Cost,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug + Cost,
+{
+    // Drops every subtree that can only build programs that cost more than
+    // `max_cost`. Arguments are bounded by whatever is left over after the
+    // cheapest choice for the others, so some programs above the bound can
+    // survive when they're mixed from different arguments.
+    pub fn prune(&mut self, id: VsaId, max_cost: usize) -> VsaId {
+        self.prune_memo(id, Bound::Cost(max_cost), &mut HashMap::new(), &mut HashMap::new())
+    }
+
+    fn prune_memo(
+        &mut self,
+        id: VsaId,
+        bound: Bound,
+        memo: &mut HashMap<(VsaId, Bound), VsaId>,
+        min_sizes: &mut HashMap<VsaId, usize>,
+    ) -> VsaId {
+        if let Some(pruned) = memo.get(&(id, bound)) {
+            return *pruned;
+        }
+
+        let pruned = match self.nodes[id].clone() {
+            Node::Leaf(asts) => {
+                let kept = asts
+                    .into_iter()
+                    .filter(|i| match bound {
+                        Bound::Cost(max) => self.asts[*i].cost() <= max,
+                        Bound::Size(max) => self.asts[*i].size() <= max,
+                    })
+                    .collect();
+                self.insert(Node::Leaf(kept))
+            }
+            Node::Union(children) => {
+                let children = children
+                    .into_iter()
+                    .map(|c| self.prune_memo(c, bound, memo, min_sizes))
+                    .collect::<Vec<_>>();
+                self.union(children)
+            }
+            Node::Join { op, children } => {
+                let (base, max) = match bound {
+                    Bound::Cost(max) => (op.cost(), max),
+                    Bound::Size(max) => (1, max),
+                };
+                let sizes = children
+                    .iter()
+                    .map(|c| self.min_size(*c, min_sizes))
+                    .collect::<Vec<_>>();
+                let total = base + sizes.iter().sum::<usize>();
+
+                if total > max {
+                    Self::EMPTY
+                } else {
+                    let children = children
+                        .iter()
+                        .zip(sizes)
+                        .map(|(c, size)| {
+                            self.prune_memo(*c, Bound::Size(max - total + size), memo, min_sizes)
+                        })
+                        .collect();
+                    self.join(op, children)
+                }
+            }
+        };
+
+        memo.insert((id, bound), pruned);
+        pruned
+    }
+
+    fn min_size(&self, id: VsaId, memo: &mut HashMap<VsaId, usize>) -> usize {
+        if let Some(size) = memo.get(&id) {
+            return *size;
+        }
+
+        let size = match &self.nodes[id] {
+            Node::Leaf(asts) => asts
+                .iter()
+                .map(|i| self.asts[*i].size())
+                .min()
+                .unwrap_or(usize::MAX),
+            Node::Union(children) => children
+                .iter()
+                .map(|c| self.min_size(*c, memo))
+                .min()
+                .unwrap_or(usize::MAX),
+            Node::Join { children, .. } => children
+                .iter()
+                .fold(1usize, |acc, c| acc.saturating_add(self.min_size(*c, memo))),
+        };
+
+        memo.insert(id, size);
+        size
+    }
+}
+
+// `AST::cost` only looks at the sizes of the arguments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Bound {
+    Cost(usize),
+    Size(usize),
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+use super::{Cost, InputLit, Language, VSA};
+
+// How many programs of each cost (or size) a VSA has
+pub type Histogram = BTreeMap<usize, u64>;
+
+type Memo<L, F, T> = HashMap<*const VSA<L, F>, T>;
+
+impl<L, F> VSA<L, F>
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug,
+{
+    // The number of programs in the VSA, saturating at `u64::MAX`. Shared
+    // sub-VSAs are only counted once, but a program that can be built in more
+    // than one way through a union is counted once per way.
+    pub fn count(&self) -> u64 {
+        self.count_memo(&mut HashMap::new())
+    }
+
+    fn count_memo(&self, memo: &mut Memo<L, F, u64>) -> u64 {
+        if let Some(n) = memo.get(&(self as *const _)) {
+            return *n;
+        }
+
+        let n = match self {
+            VSA::Leaf(s) => s.len() as u64,
+            VSA::Union(vsas) => vsas
+                .iter()
+                .fold(0u64, |acc, vsa| acc.saturating_add(vsa.count_memo(memo))),
+            VSA::Join { children, .. } => children
+                .iter()
+                .fold(1u64, |acc, vsa| acc.saturating_mul(vsa.count_memo(memo))),
+        };
+
+        memo.insert(self as *const _, n);
+        n
+    }
+
+    pub fn size_histogram(&self) -> Histogram {
+        self.size_histogram_memo(&mut HashMap::new())
+    }
+
+    fn size_histogram_memo(&self, memo: &mut Memo<L, F, Histogram>) -> Histogram {
+        if let Some(hist) = memo.get(&(self as *const _)) {
+            return hist.clone();
+        }
+
+        let hist = match self {
+            VSA::Leaf(s) => s.iter().fold(Histogram::new(), |mut hist, ast| {
+                add(&mut hist, ast.size(), 1);
+                hist
+            }),
+            VSA::Union(vsas) => merge(vsas.iter().map(|vsa| vsa.size_histogram_memo(memo))),
+            VSA::Join { children, .. } => shift(&convolve_children(children, memo), 1),
+        };
+
+        memo.insert(self as *const _, hist.clone());
+        hist
+    }
+}
+
+impl<L, F> VSA<L, F>
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit + Cost,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug + Cost,
+{
+    // Like `size_histogram`, but by `Cost::cost`. The cost of an application
+    // only depends on its function and the sizes of its arguments, so the
+    // children only need their size histograms.
+    pub fn cost_histogram(&self) -> Histogram {
+        self.cost_histogram_memo(&mut HashMap::new(), &mut HashMap::new())
+    }
+
+    fn cost_histogram_memo(
+        &self,
+        memo: &mut Memo<L, F, Histogram>,
+        sizes: &mut Memo<L, F, Histogram>,
+    ) -> Histogram {
+        if let Some(hist) = memo.get(&(self as *const _)) {
+            return hist.clone();
+        }
+
+        let hist = match self {
+            VSA::Leaf(s) => s.iter().fold(Histogram::new(), |mut hist, ast| {
+                add(&mut hist, ast.cost(), 1);
+                hist
+            }),
+            VSA::Union(vsas) => merge(vsas.iter().map(|vsa| vsa.cost_histogram_memo(memo, sizes))),
+            VSA::Join { op, children } => shift(&convolve_children(children, sizes), op.cost()),
+        };
+
+        memo.insert(self as *const _, hist.clone());
+        hist
+    }
+}
+
+// the sizes of every combination of arguments
+fn convolve_children<L, F>(
+    children: &[std::rc::Rc<VSA<L, F>>],
+    memo: &mut Memo<L, F, Histogram>,
+) -> Histogram
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug,
+{
+    children.iter().fold(Histogram::from([(0, 1)]), |acc, child| {
+        let child = child.size_histogram_memo(memo);
+        let mut res = Histogram::new();
+        for (a, n) in acc.iter() {
+            for (b, m) in child.iter() {
+                add(&mut res, a + b, n.saturating_mul(*m));
+            }
+        }
+        res
+    })
+}
+
+fn add(hist: &mut Histogram, key: usize, n: u64) {
+    if n > 0 {
+        let entry = hist.entry(key).or_insert(0);
+        *entry = entry.saturating_add(n);
+    }
+}
+
+fn merge(hists: impl IntoIterator<Item = Histogram>) -> Histogram {
+    hists.into_iter().fold(Histogram::new(), |mut acc, hist| {
+        for (k, n) in hist {
+            add(&mut acc, k, n);
+        }
+        acc
+    })
+}
+
+fn shift(hist: &Histogram, by: usize) -> Histogram {
+    hist.iter().map(|(k, n)| (k + by, *n)).collect()
+}
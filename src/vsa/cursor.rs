@@ -0,0 +1,173 @@
+use std::{hash::Hash, rc::Rc};
+
+use super::{
+    arena::{Arena, VsaId},
+    InputLit, Language, AST, VSA,
+};
+
+// One of the things a cursor can go into or pin from where it is: an
+// alternative of a union, a program of a leaf, or an argument of a join
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alternative<L, F>
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug,
+{
+    pub index: usize,
+    pub best: Option<AST<L, F>>,
+    pub cost: Option<usize>,
+}
+
+// Walks down a VSA and narrows it one choice at a time. Every pin replaces the
+// node under the cursor, so `root` is always the VSA with all the choices
+// made so far and `best` is what `pick_best` picks from it. The root is
+// also kept in an arena, so picking doesn't blow up on shared sub-VSAs.
+pub struct Cursor<L, F>
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug,
+{
+    root: Rc<VSA<L, F>>,
+    arena: Arena<L, F>,
+    root_id: VsaId,
+    // child positions from the root to the node under the cursor
+    path: Vec<usize>,
+}
+
+impl<L, F> Cursor<L, F>
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug,
+{
+    pub fn new(root: Rc<VSA<L, F>>) -> Self {
+        let mut arena = Arena::new();
+        let root_id = arena.import(&root);
+        Cursor {
+            root,
+            arena,
+            root_id,
+            path: Vec::new(),
+        }
+    }
+
+    pub fn root(&self) -> Rc<VSA<L, F>> {
+        self.root.clone()
+    }
+
+    pub fn path(&self) -> &[usize] {
+        &self.path
+    }
+
+    pub fn current(&self) -> Rc<VSA<L, F>> {
+        self.path
+            .iter()
+            .fold(self.root.clone(), |vsa, i| child(&vsa, *i).unwrap())
+    }
+
+    pub fn best(&self, rank: impl Fn(&AST<L, F>) -> usize + Copy) -> Option<AST<L, F>> {
+        self.arena.pick_best(self.root_id, rank)
+    }
+
+    // Importing a child only adds the nodes the arena hasn't seen yet
+    pub fn alternatives(&mut self, rank: impl Fn(&AST<L, F>) -> usize + Copy) -> Vec<Alternative<L, F>> {
+        let current = self.current();
+        (0..children_len(&current))
+            .map(|index| {
+                let best = child(&current, index).and_then(|vsa| {
+                    let id = self.arena.import(&vsa);
+                    self.arena.pick_best(id, rank)
+                });
+                Alternative {
+                    index,
+                    cost: best.as_ref().map(rank),
+                    best,
+                }
+            })
+            .collect()
+    }
+
+    // Leaf programs can be pinned, but not gone into
+    pub fn down(&mut self, i: usize) -> bool {
+        match self.current().as_ref() {
+            VSA::Union(children) | VSA::Join { children, .. } if i < children.len() => {
+                self.path.push(i);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn up(&mut self) -> bool {
+        self.path.pop().is_some()
+    }
+
+    // Keeps only alternative `i` of the union or leaf under the cursor and
+    // returns the new root. Joins have nothing to choose between, so pinning
+    // one does nothing and returns None.
+    pub fn pin(&mut self, i: usize) -> Option<Rc<VSA<L, F>>> {
+        let current = self.current();
+        let pinned = match current.as_ref() {
+            VSA::Join { .. } => return None,
+            _ => child(&current, i)?,
+        };
+
+        self.root = replace(&self.root, &self.path, pinned);
+        self.root_id = self.arena.import(&self.root);
+        Some(self.root.clone())
+    }
+}
+
+fn children_len<L, F>(vsa: &VSA<L, F>) -> usize
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug,
+{
+    match vsa {
+        VSA::Leaf(asts) => asts.len(),
+        VSA::Union(children) | VSA::Join { children, .. } => children.len(),
+    }
+}
+
+// A leaf's programs are in no particular order, so they're numbered by their
+// position in `Debug` order
+fn child<L, F>(vsa: &VSA<L, F>, i: usize) -> Option<Rc<VSA<L, F>>>
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug,
+{
+    match vsa {
+        VSA::Leaf(asts) => {
+            let mut asts = asts.iter().collect::<Vec<_>>();
+            asts.sort_by_cached_key(|ast| format!("{ast:?}"));
+            asts.get(i)
+                .map(|ast| Rc::new(VSA::Leaf(std::iter::once((*ast).clone()).collect())))
+        }
+        VSA::Union(children) | VSA::Join { children, .. } => children.get(i).cloned(),
+    }
+}
+
+// copies the nodes along `path` with the last one swapped for `new`
+fn replace<L, F>(vsa: &Rc<VSA<L, F>>, path: &[usize], new: Rc<VSA<L, F>>) -> Rc<VSA<L, F>>
+where
+    L: Clone + Eq + Hash + std::fmt::Debug + InputLit,
+    F: Language<L> + Eq + Copy + Hash + std::fmt::Debug,
+{
+    let Some((i, rest)) = path.split_first() else {
+        return new;
+    };
+
+    let with = |children: &[Rc<VSA<L, F>>]| {
+        let mut children = children.to_vec();
+        children[*i] = replace(&children[*i], rest, new.clone());
+        children
+    };
+
+    Rc::new(match vsa.as_ref() {
+        VSA::Union(children) => VSA::Union(with(children)),
+        VSA::Join { op, children } => VSA::Join {
+            op: *op,
+            children: with(children),
+        },
+        VSA::Leaf(_) => unreachable!("the cursor never goes into a leaf"),
+    })
+}
@@ -0,0 +1,168 @@
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+
+use super::{Fun, Lit, Type};
+
+type EvalFn = dyn Fn(&[Lit], &Lit) -> Lit + Send + Sync;
+type WitnessFn = dyn Fn(&Lit, &Lit) -> Vec<Vec<Lit>> + Send + Sync;
+
+// A function that isn't built into `Fun`, registered at runtime with
+// `register`. It's referred to as `Fun::Custom(id)` everywhere else.
+#[derive(Clone)]
+pub struct CustomFun {
+    pub name: String,
+    pub args: Vec<Type>,
+    pub ret: Type,
+    pub cost: usize,
+    eval: Arc<EvalFn>,
+    witness: Option<Arc<WitnessFn>>,
+}
+
+impl std::fmt::Debug for CustomFun {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CustomFun")
+            .field("name", &self.name)
+            .field("args", &self.args)
+            .field("ret", &self.ret)
+            .field("cost", &self.cost)
+            .field("witness", &self.witness.is_some())
+            .finish()
+    }
+}
+
+impl CustomFun {
+    // `eval` only ever gets arguments of the types in `args`, with the input
+    // already substituted in
+    pub fn new(
+        name: impl Into<String>,
+        args: Vec<Type>,
+        ret: Type,
+        eval: impl Fn(&[Lit]) -> Lit + Send + Sync + 'static,
+    ) -> Self {
+        CustomFun::with_input(name, args, ret, move |args, _| eval(args))
+    }
+
+    // For functions that also look at the input themselves, like anything
+    // that has a `Slice` in it
+    pub fn with_input(
+        name: impl Into<String>,
+        args: Vec<Type>,
+        ret: Type,
+        eval: impl Fn(&[Lit], &Lit) -> Lit + Send + Sync + 'static,
+    ) -> Self {
+        CustomFun {
+            name: name.into(),
+            args,
+            ret,
+            cost: 1,
+            eval: Arc::new(eval),
+            witness: None,
+        }
+    }
+
+    pub fn cost(mut self, cost: usize) -> Self {
+        self.cost = cost;
+        self
+    }
+
+    // Given the input and the output, every tuple of argument values that
+    // could have produced that output. Without a witness the function is only
+    // found bottom up.
+    pub fn witness(
+        mut self,
+        witness: impl Fn(&Lit, &Lit) -> Vec<Vec<Lit>> + Send + Sync + 'static,
+    ) -> Self {
+        self.witness = Some(Arc::new(witness));
+        self
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<Vec<CustomFun>> = RwLock::new(Vec::new());
+}
+
+// Names are unique, and builtins like `Concat` can't be shadowed, since
+// `parse_sketch` and the text formats look functions up by name
+pub fn register(fun: CustomFun) -> Result<Fun, String> {
+    if Fun::ALL.iter().any(|builtin| builtin.name() == fun.name) {
+        return Err(format!("{} is a builtin", fun.name));
+    }
+    let mut registry = REGISTRY.write().unwrap();
+    if registry.iter().any(|f| f.name == fun.name) {
+        return Err(format!("{} is already registered", fun.name));
+    }
+    registry.push(fun);
+    Ok(Fun::Custom(registry.len() - 1))
+}
+
+// Swaps in a new definition for a function that's already registered, so
+// the old `Fun::Custom` keeps working with it
+pub fn replace(fun: CustomFun) -> Result<Fun, String> {
+    let mut registry = REGISTRY.write().unwrap();
+    let id = registry
+        .iter()
+        .position(|f| f.name == fun.name)
+        .ok_or_else(|| format!("{} isn't registered", fun.name))?;
+    registry[id] = fun;
+    Ok(Fun::Custom(id))
+}
+
+pub fn registered() -> Vec<Fun> {
+    (0..REGISTRY.read().unwrap().len()).map(Fun::Custom).collect()
+}
+
+pub fn lookup(name: &str) -> Option<Fun> {
+    REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .position(|f| f.name == name)
+        .map(Fun::Custom)
+}
+
+pub fn get(id: usize) -> CustomFun {
+    REGISTRY.read().unwrap()[id].clone()
+}
+
+// `Fun` asks for these all the time, so they don't copy the closures
+pub(crate) fn name(id: usize) -> String {
+    REGISTRY.read().unwrap()[id].name.clone()
+}
+
+pub(crate) fn args(id: usize) -> Vec<Type> {
+    REGISTRY.read().unwrap()[id].args.clone()
+}
+
+pub(crate) fn ret(id: usize) -> Type {
+    REGISTRY.read().unwrap()[id].ret
+}
+
+pub(crate) fn cost(id: usize) -> usize {
+    REGISTRY.read().unwrap()[id].cost
+}
+
+// the lock isn't held while the closures run, so they can use the registry
+pub(crate) fn eval(id: usize, args: &[Lit], inp: &Lit) -> Lit {
+    let eval = REGISTRY.read().unwrap()[id].eval.clone();
+    eval(args, inp)
+}
+
+// A witness is user code, so tuples that don't fit the signature are dropped
+// here rather than tripping up `learn` later
+pub(crate) fn witness(id: usize, inp: &Lit, out: &Lit) -> Vec<Vec<Lit>> {
+    let (witness, arg_types) = {
+        let registry = REGISTRY.read().unwrap();
+        (registry[id].witness.clone(), registry[id].args.clone())
+    };
+    let Some(witness) = witness else {
+        return Vec::new();
+    };
+    witness(inp, out)
+        .into_iter()
+        .filter(|args| {
+            args.len() == arg_types.len()
+                && args.iter().zip(&arg_types).all(|(arg, ty)| arg.ty() == *ty)
+        })
+        .collect()
+}
@@ -0,0 +1,144 @@
+use std::{collections::HashMap, fmt::Write};
+
+use itertools::Itertools;
+
+use super::{Fun, Lit, VSA};
+
+type StringVSA = VSA<Lit, Fun>;
+
+// The nodes of a VSA numbered in preorder, where a shared sub-VSA keeps the
+// number it got the first time it was reached. Leaf programs are sorted so
+// that the same VSA always exports the same way.
+struct Graph<'a> {
+    nodes: Vec<&'a StringVSA>,
+    // (from, to, position of `to` among the children of `from`)
+    edges: Vec<(usize, usize, usize)>,
+}
+
+impl<'a> Graph<'a> {
+    fn new(root: &'a StringVSA) -> Self {
+        let mut graph = Graph {
+            nodes: Vec::new(),
+            edges: Vec::new(),
+        };
+        graph.visit(root, &mut HashMap::new());
+        graph
+    }
+
+    fn visit(&mut self, vsa: &'a StringVSA, ids: &mut HashMap<*const StringVSA, usize>) -> usize {
+        if let Some(id) = ids.get(&(vsa as *const _)) {
+            return *id;
+        }
+
+        let id = self.nodes.len();
+        self.nodes.push(vsa);
+        ids.insert(vsa as *const _, id);
+
+        if let VSA::Union(children) | VSA::Join { children, .. } = vsa {
+            for (i, child) in children.iter().enumerate() {
+                let to = self.visit(child, ids);
+                self.edges.push((id, to, i));
+            }
+        }
+
+        id
+    }
+}
+
+fn programs(vsa: &StringVSA) -> Vec<String> {
+    match vsa {
+        VSA::Leaf(asts) => asts.iter().map(|ast| ast.to_string()).sorted().collect(),
+        _ => Vec::new(),
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn dot_string(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl VSA<Lit, Fun> {
+    // {
+    //   "root": 0,
+    //   "nodes": [
+    //     { "id": 0, "kind": "union" },
+    //     { "id": 1, "kind": "join", "op": "Concat" },
+    //     { "id": 2, "kind": "leaf", "programs": ["X", "' '"] }
+    //   ],
+    //   "edges": [{ "from": 0, "to": 1, "index": 0 }, ...]
+    // }
+    //
+    // `index` is the argument position for joins and the alternative for
+    // unions. Programs are printed with `Display`.
+    pub fn to_json(&self) -> String {
+        let graph = Graph::new(self);
+
+        let nodes = graph
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(id, vsa)| match vsa {
+                VSA::Leaf(_) => format!(
+                    r#"{{"id":{id},"kind":"leaf","programs":[{}]}}"#,
+                    programs(vsa).iter().map(|p| json_string(p)).join(",")
+                ),
+                VSA::Union(_) => format!(r#"{{"id":{id},"kind":"union"}}"#),
+                VSA::Join { op, .. } => format!(
+                    r#"{{"id":{id},"kind":"join","op":{}}}"#,
+                    json_string(&op.name())
+                ),
+            })
+            .join(",");
+
+        let edges = graph
+            .edges
+            .iter()
+            .map(|(from, to, i)| format!(r#"{{"from":{from},"to":{to},"index":{i}}}"#))
+            .join(",");
+
+        format!(r#"{{"root":0,"nodes":[{nodes}],"edges":[{edges}]}}"#)
+    }
+
+    // Leaves are boxes listing their programs, unions are ∪ and joins are
+    // labelled with their function. Join edges are labelled with the
+    // argument position.
+    pub fn to_dot(&self) -> String {
+        let graph = Graph::new(self);
+        let mut out = String::from("digraph vsa {\n");
+
+        for (id, vsa) in graph.nodes.iter().enumerate() {
+            let (shape, label) = match vsa {
+                VSA::Leaf(_) => ("box", programs(vsa).iter().map(|p| dot_string(p)).join("\\n")),
+                VSA::Union(_) => ("circle", "∪".to_string()),
+                VSA::Join { op, .. } => ("ellipse", dot_string(&op.name())),
+            };
+            writeln!(out, "    n{id} [shape={shape}, label=\"{label}\"];").unwrap();
+        }
+
+        for (from, to, i) in graph.edges.iter() {
+            match graph.nodes[*from] {
+                VSA::Join { .. } => writeln!(out, "    n{from} -> n{to} [label=\"{i}\"];").unwrap(),
+                _ => writeln!(out, "    n{from} -> n{to};").unwrap(),
+            }
+        }
+
+        out.push_str("}\n");
+        out
+    }
+}